@@ -0,0 +1,164 @@
+use std::{
+    collections::BTreeSet,
+    path::PathBuf,
+    sync::{
+        Arc,
+        atomic::{self, AtomicBool, AtomicUsize},
+    },
+    thread::{self, JoinHandle},
+};
+
+use egui::Context;
+
+use crate::{
+    filter::DuplicateFilters,
+    report::{Redundancy, UniqueContent},
+    scan::{Entry, EntryInfo},
+    tree::Roots,
+    utils::TryJoin,
+};
+
+/// A set of duplicates with their redundant bytes.
+pub type Duplicate = (u64, EntryInfo, BTreeSet<PathBuf>);
+
+const STEPS: [&str; 4] = [
+    "grouping entries",
+    "finding unique content",
+    "breaking down redundancy",
+    "filtering duplicates",
+];
+
+/// Everything that is calculated from the enabled drives.
+#[derive(Default)]
+pub struct Analysis {
+    /// All enabled drives by their name.
+    pub drives: Roots,
+    pub unique: Vec<UniqueContent>,
+    pub redundancy: Redundancy,
+    pub redundant_bytes: u64,
+    /// Sorted from least to most redundant bytes.
+    pub duplicates: Vec<Duplicate>,
+}
+
+/// Runs an [`Analysis`] in the background.
+///
+/// Dropping the job cancels it, but its thread only stops once it notices, which it checks
+/// between steps, between groups of duplicates and while grouping and filtering them.
+pub struct AnalysisJob {
+    state: Arc<JobState>,
+    join_handle: Option<JoinHandle<Option<Analysis>>>,
+}
+
+#[derive(Default)]
+struct JobState {
+    canceled: AtomicBool,
+    step: AtomicUsize,
+}
+
+impl AnalysisJob {
+    pub fn start(drives: Roots, filters: DuplicateFilters, ctx: Context) -> Self {
+        let state = Arc::new(JobState::default());
+        let join_handle = thread::spawn({
+            let state = state.clone();
+            move || {
+                let analysis = analyze(drives, &filters, &state, &ctx);
+                ctx.request_repaint();
+                analysis
+            }
+        });
+
+        Self {
+            state,
+            join_handle: Some(join_handle),
+        }
+    }
+
+    /// Returns the current step and the progress as a fraction.
+    pub fn progress(&self) -> (&'static str, f32) {
+        let step = self.state.step.load(atomic::Ordering::Relaxed);
+        (STEPS[step], step as f32 / STEPS.len() as f32)
+    }
+
+    /// Returns the analysis once it is done, unless it was canceled.
+    pub fn try_finish(&mut self) -> Option<Analysis> {
+        self.join_handle
+            .try_join()
+            .and_then(|analysis| analysis.expect("analysis shouldn't panic"))
+    }
+
+    /// Asks the job to stop, without waiting for it.
+    pub fn cancel(&self) {
+        self.state.canceled.store(true, atomic::Ordering::Relaxed);
+    }
+
+    /// Whether the thread of the job has stopped, either because it is done or was canceled.
+    pub fn is_finished(&self) -> bool {
+        self.join_handle
+            .as_ref()
+            .is_none_or(JoinHandle::is_finished)
+    }
+}
+
+impl Drop for AnalysisJob {
+    fn drop(&mut self) {
+        self.cancel();
+    }
+}
+
+impl JobState {
+    /// Moves on to the next step and returns `false` if the job was canceled in the meantime.
+    fn next_step(&self, ctx: &Context) -> bool {
+        self.step.fetch_add(1, atomic::Ordering::Relaxed);
+        ctx.request_repaint();
+        !self.canceled.load(atomic::Ordering::Relaxed)
+    }
+}
+
+fn analyze(
+    drives: Roots,
+    filters: &DuplicateFilters,
+    state: &JobState,
+    ctx: &Context,
+) -> Option<Analysis> {
+    let grouped = drives.grouped(&state.canceled)?;
+
+    state.next_step(ctx).then_some(())?;
+    let unique = UniqueContent::per_drive(&drives, &grouped);
+
+    state.next_step(ctx).then_some(())?;
+    let redundancy = Redundancy::new(&drives, &grouped);
+
+    state.next_step(ctx).then_some(())?;
+    let mut redundant_bytes = 0;
+    let mut duplicates = Vec::new();
+    for unfiltered_duplicates in filters.apply(&drives, Entry::unfiltered_duplicates(grouped)) {
+        if state.canceled.load(atomic::Ordering::Relaxed) {
+            return None;
+        }
+
+        redundant_bytes += Entry::redundant_bytes(&unfiltered_duplicates);
+        duplicates.extend(
+            drives
+                .filter_duplicates_by_prefix(unfiltered_duplicates, &state.canceled)?
+                .into_iter()
+                .map(|(info, ids)| {
+                    let paths = ids
+                        .into_iter()
+                        .map(|id| drives.path(id))
+                        .collect::<BTreeSet<_>>();
+                    (info.bytes * (paths.len() as u64 - 1), info, paths)
+                }),
+        );
+    }
+    duplicates.sort_unstable_by_key(|(redundant_bytes, info, paths)| {
+        (*redundant_bytes, info.kind, paths.len())
+    });
+
+    Some(Analysis {
+        drives,
+        unique,
+        redundancy,
+        redundant_bytes,
+        duplicates,
+    })
+}
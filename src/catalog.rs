@@ -0,0 +1,673 @@
+use std::{
+    fmt,
+    fs::{self, File},
+    io::{self, Read, Write},
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    scan::{Entry, ScanOptions},
+    tree::Tree,
+};
+
+/// Marks catalogs that start with a version and carry more than just the bare [`Entry`].
+///
+/// Older catalogs are nothing but a postcard encoded [`Entry`] and are still supported.
+const MAGIC: &[u8; 8] = b"SSDEDUPE";
+/// Appended to the path of a catalog for its previous version.
+const BACKUP_SUFFIX: &str = ".bak";
+/// Appended to the path of a catalog while it is written.
+const TEMP_SUFFIX: &str = ".tmp";
+/// The [`Tree`] follows a header with everything else, so that it can be loaded only when needed.
+///
+/// The header says how the tree is compressed and contains its checksum and its length, so that
+/// catalogs that were cut short are noticed as soon as they are opened.
+const VERSION: u32 = 1;
+
+/// The contents of a `.fsinfo` file.
+#[derive(Clone, Debug)]
+pub struct Catalog {
+    /// The path that was scanned or `None` for catalogs that predate tracking it.
+    pub root: Option<PathBuf>,
+    pub options: ScanOptions,
+    /// Applies the next time the catalog is written.
+    pub compression: Compression,
+    summary: Summary,
+    /// Shared with running analyses, so that they don't have to copy it.
+    ///
+    /// `None` until it is [loaded](Catalog::load_tree).
+    tree: Option<Arc<Tree>>,
+}
+
+/// What is known about a catalog without loading its [`Tree`].
+#[derive(Clone, Copy, Debug, Serialize, Deserialize)]
+pub struct Summary {
+    pub dirs: u64,
+    pub files: u64,
+    pub bytes: u64,
+    /// Whether the scan was canceled before it finished.
+    pub partial: bool,
+}
+
+/// How the [`Tree`] of a catalog is stored.
+///
+/// File names make up most of a tree and repeat a lot, so they compress well.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Compression {
+    None,
+    #[default]
+    Zstd,
+}
+
+/// Everything but the tree of a catalog.
+#[derive(Deserialize)]
+struct Header {
+    root: Option<PathBuf>,
+    options: ScanOptions,
+    summary: Summary,
+    compression: Compression,
+    /// The CRC-32 of the encoded tree.
+    checksum: u32,
+    /// The length of the encoded tree.
+    tree_len: u64,
+}
+
+impl Catalog {
+    pub fn new(
+        root: Option<PathBuf>,
+        options: ScanOptions,
+        compression: Compression,
+        tree: Tree,
+    ) -> Self {
+        Self {
+            root,
+            options,
+            compression,
+            summary: Summary::new(&tree),
+            tree: Some(Arc::new(tree)),
+        }
+    }
+
+    /// Reads the catalog at `path`, but not its tree, unless it is a bare [`Entry`] without a
+    /// header.
+    pub fn open(path: &Path) -> Result<Self, CatalogError> {
+        match read_header(path)? {
+            Some(header) => Ok(Self {
+                root: header.root,
+                options: header.options,
+                compression: header.compression,
+                summary: header.summary,
+                tree: None,
+            }),
+            None => Self::from_bytes(&fs::read(path)?),
+        }
+    }
+
+    pub fn summary(&self) -> Summary {
+        self.summary
+    }
+
+    /// Returns the tree if it was already loaded.
+    pub fn tree(&self) -> Option<&Arc<Tree>> {
+        self.tree.as_ref()
+    }
+
+    /// Returns the tree, loading it from the catalog at `path` first if necessary.
+    pub fn load_tree(&mut self, path: &Path) -> Result<&Arc<Tree>, CatalogError> {
+        if self.tree.is_none() {
+            self.tree = Some(Arc::new(Self::read_tree(path)?));
+        }
+
+        Ok(self.tree.as_ref().unwrap())
+    }
+
+    /// Reads the tree of the catalog at `path`, e.g. to load it in the background and
+    /// [set](Catalog::set_tree) it once it is done.
+    pub fn read_tree(path: &Path) -> Result<Tree, CatalogError> {
+        let data = fs::read(path)?;
+        match split_header(&data)? {
+            Some((header, tree)) => {
+                if header.tree_len != tree.len() as u64 {
+                    return Err(CatalogError::LengthMismatch);
+                }
+                if header.checksum != crc32fast::hash(tree) {
+                    return Err(CatalogError::ChecksumMismatch);
+                }
+
+                Ok(match header.compression {
+                    Compression::None => postcard::from_bytes(tree)?,
+                    Compression::Zstd => postcard::from_bytes(&zstd::decode_all(tree)?)?,
+                })
+            }
+            None => Ok(Arc::unwrap_or_clone(Self::from_bytes(&data)?.tree.unwrap())),
+        }
+    }
+
+    /// Applies a batch of `updates` to the loaded tree like [`Tree::update`] and returns whether
+    /// anything was updated.
+    ///
+    /// A tree that is shared, e.g. with the UI or an analysis, is copied first, so this should be
+    /// called in the background.
+    pub fn update_tree(&mut self, updates: Vec<(PathBuf, Option<Entry>)>) -> bool {
+        let Some(tree) = &mut self.tree else {
+            return false;
+        };
+        if updates.is_empty() {
+            return false;
+        }
+
+        let tree = Arc::make_mut(tree);
+        let updated = tree.update(updates, self.options.dir_hashing);
+        self.summary = Summary::new(tree);
+        updated
+    }
+
+    /// Replaces the tree, e.g. after it was loaded in the background or rehashed.
+    pub fn set_tree(&mut self, tree: Tree) {
+        self.summary = Summary::new(&tree);
+        self.tree = Some(Arc::new(tree));
+    }
+
+    /// Encodes the catalog, whose tree has to be loaded.
+    pub fn to_bytes(&self) -> Result<Vec<u8>, CatalogError> {
+        let tree = self
+            .tree
+            .as_ref()
+            .expect("only loaded catalogs should be written");
+        let tree = match self.compression {
+            Compression::None => postcard::to_allocvec(tree)?,
+            Compression::Zstd => zstd::encode_all(&*postcard::to_allocvec(tree)?, 0)?,
+        };
+        let mut data = postcard::to_extend(
+            &(
+                VERSION,
+                &self.root,
+                self.options,
+                self.summary,
+                self.compression,
+                crc32fast::hash(&tree),
+                tree.len() as u64,
+            ),
+            MAGIC.to_vec(),
+        )?;
+        data.extend(tree);
+        Ok(data)
+    }
+
+    /// Opens the backup of the catalog at `path` and loads its tree, verifying that it is intact.
+    ///
+    /// Saving the result to `path` recovers the previous version of a broken catalog.
+    pub fn open_backup(path: &Path) -> Result<Self, CatalogError> {
+        let backup_path = backup_path(path);
+        let mut catalog = Self::open(&backup_path)?;
+        catalog.load_tree(&backup_path)?;
+        Ok(catalog)
+    }
+
+    /// Writes the catalog to `path`, keeping the previous version as a [backup](backup_path).
+    ///
+    /// The catalog is written to a temporary file first and then moved into place, so that a
+    /// crash never leaves a partially written catalog behind.
+    pub fn save(&self, path: &Path) -> Result<(), CatalogError> {
+        let data = self.to_bytes()?;
+        let temp_path = with_suffix(path, TEMP_SUFFIX);
+        let write = || {
+            let mut file = File::create(&temp_path)?;
+            file.write_all(&data)?;
+            file.sync_all()
+        };
+        if let Err(error) = write() {
+            let _ = fs::remove_file(&temp_path);
+            return Err(error.into());
+        }
+
+        let backup_path = backup_path(path);
+        match fs::remove_file(&backup_path) {
+            Err(error) if error.kind() != io::ErrorKind::NotFound => return Err(error.into()),
+            _ => {}
+        }
+        // the backup shares the previous version with the catalog until it is replaced, unless
+        // the file system doesn't support hard links
+        match fs::hard_link(path, &backup_path) {
+            Err(error) if error.kind() == io::ErrorKind::NotFound => {}
+            Err(_) => {
+                fs::copy(path, &backup_path)?;
+            }
+            Ok(()) => {}
+        }
+
+        fs::rename(&temp_path, path)?;
+        sync_parent(path)?;
+        Ok(())
+    }
+
+    /// Reads a catalog that is nothing but a bare [`Entry`].
+    fn from_bytes(data: &[u8]) -> Result<Self, CatalogError> {
+        Ok(Self::new(
+            None,
+            legacy::OPTIONS,
+            Compression::None,
+            Tree::new(postcard::from_bytes::<legacy::Entry>(data)?.into()),
+        ))
+    }
+}
+
+impl Summary {
+    fn new(tree: &Tree) -> Self {
+        let root = tree.root();
+        Self {
+            dirs: tree.dirs(),
+            files: tree.files(),
+            bytes: root.info().bytes,
+            partial: root.is_partial(),
+        }
+    }
+}
+
+/// Splits a catalog into its header and its encoded tree or returns `None` if it is a bare
+/// [`Entry`] without a header.
+fn split_header(data: &[u8]) -> Result<Option<(Header, &[u8])>, CatalogError> {
+    let Some(data) = data.strip_prefix(MAGIC) else {
+        return Ok(None);
+    };
+
+    let (version, data) = postcard::take_from_bytes::<u32>(data)?;
+    if version != VERSION {
+        return Err(CatalogError::UnsupportedVersion(version));
+    }
+    Ok(Some(postcard::take_from_bytes(data)?))
+}
+
+/// Renames the catalog at `from` along with its backup.
+pub fn rename(from: &Path, to: &Path) -> io::Result<()> {
+    fs::rename(from, to)?;
+    match fs::rename(backup_path(from), backup_path(to)) {
+        Err(error) if error.kind() != io::ErrorKind::NotFound => Err(error),
+        _ => Ok(()),
+    }
+}
+
+/// Deletes the catalog at `path` along with its backup.
+pub fn remove(path: &Path) -> io::Result<()> {
+    fs::remove_file(path)?;
+    match fs::remove_file(backup_path(path)) {
+        Err(error) if error.kind() != io::ErrorKind::NotFound => Err(error),
+        _ => Ok(()),
+    }
+}
+
+/// The path of the previous version of the catalog at `path`.
+pub fn backup_path(path: &Path) -> PathBuf {
+    with_suffix(path, BACKUP_SUFFIX)
+}
+
+fn with_suffix(path: &Path, suffix: &str) -> PathBuf {
+    let mut path = path.as_os_str().to_owned();
+    path.push(suffix);
+    path.into()
+}
+
+/// Makes sure that renaming a file inside the parent of `path` is persisted.
+#[cfg(unix)]
+fn sync_parent(path: &Path) -> io::Result<()> {
+    match path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => File::open(parent)?.sync_all(),
+        _ => Ok(()),
+    }
+}
+
+/// Directories cannot be synced on other platforms, where renaming is persisted anyway.
+#[cfg(not(unix))]
+fn sync_parent(_path: &Path) -> io::Result<()> {
+    Ok(())
+}
+
+/// Reads the header of the catalog at `path` without reading its tree, or returns `None` if the
+/// catalog is a bare [`Entry`] without a header.
+///
+/// Fails if the file doesn't have the length that the header expects.
+fn read_header(path: &Path) -> Result<Option<Header>, CatalogError> {
+    let mut file = File::open(path)?;
+    let file_len = file.metadata()?.len();
+    let mut data = Vec::new();
+    // headers are small, unless their root is a very long path
+    let mut len = 4096;
+    loop {
+        let missing = len - data.len();
+        (&mut file).take(missing as u64).read_to_end(&mut data)?;
+        match split_header(&data) {
+            Err(CatalogError::Postcard(postcard::Error::DeserializeUnexpectedEnd))
+                if data.len() == len =>
+            {
+                len *= 2;
+            }
+            Ok(Some((header, tree))) => {
+                let header_len = (data.len() - tree.len()) as u64;
+                if header_len.checked_add(header.tree_len) != Some(file_len) {
+                    return Err(CatalogError::LengthMismatch);
+                }
+                return Ok(Some(header));
+            }
+            Ok(None) => return Ok(None),
+            Err(error) => return Err(error),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum CatalogError {
+    Io(io::Error),
+    Postcard(postcard::Error),
+    ChecksumMismatch,
+    /// The catalog is longer or shorter than its header says, e.g. because it was cut short.
+    LengthMismatch,
+    UnsupportedVersion(u32),
+}
+
+impl fmt::Display for CatalogError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Io(error) => error.fmt(f),
+            Self::Postcard(error) => error.fmt(f),
+            Self::ChecksumMismatch => write!(f, "catalog is corrupted (checksum mismatch)"),
+            Self::LengthMismatch => write!(f, "catalog is corrupted (length mismatch)"),
+            Self::UnsupportedVersion(version) => {
+                write!(f, "unsupported catalog version {version}")
+            }
+        }
+    }
+}
+
+impl std::error::Error for CatalogError {}
+
+impl From<io::Error> for CatalogError {
+    fn from(error: io::Error) -> Self {
+        Self::Io(error)
+    }
+}
+
+impl From<postcard::Error> for CatalogError {
+    fn from(error: postcard::Error) -> Self {
+        Self::Postcard(error)
+    }
+}
+
+/// The format of catalogs from before they had a header.
+mod legacy {
+    use std::collections::BTreeMap;
+
+    use compact_str::CompactString;
+    use serde::Deserialize;
+
+    use crate::scan::{self, DirHashing, EntryInfo};
+
+    /// The options that catalogs from before they had a header were scanned with.
+    pub const OPTIONS: scan::ScanOptions = scan::ScanOptions {
+        count: false,
+        archives: false,
+        dir_hashing: DirHashing::Content,
+    };
+
+    /// An [`Entry`](scan::Entry) from before directories could be partial.
+    #[derive(Deserialize)]
+    pub enum Entry {
+        Dir(Dir),
+        File(EntryInfo),
+    }
+
+    #[derive(Deserialize)]
+    pub struct Dir {
+        info: EntryInfo,
+        dirs: u64,
+        files: u64,
+        entries: BTreeMap<CompactString, Entry>,
+    }
+
+    impl From<Entry> for scan::Entry {
+        fn from(entry: Entry) -> Self {
+            match entry {
+                Entry::Dir(dir) => Self::Dir(scan::Dir {
+                    info: dir.info,
+                    dirs: dir.dirs,
+                    files: dir.files,
+                    entries: dir
+                        .entries
+                        .into_iter()
+                        .map(|(file_name, entry)| (file_name, entry.into()))
+                        .collect(),
+                    partial: false,
+                }),
+                Entry::File(info) => Self::File(info),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use compact_str::CompactString;
+
+    use super::*;
+    use crate::scan::{
+        DirHashing, EntryInfo,
+        tests::{dir, file},
+    };
+
+    /// A [`legacy::Entry`], which can only be read, written the way it used to be.
+    #[derive(Serialize)]
+    enum LegacyEntry {
+        Dir(LegacyDir),
+        File(EntryInfo),
+    }
+
+    #[derive(Serialize)]
+    struct LegacyDir {
+        info: EntryInfo,
+        dirs: u64,
+        files: u64,
+        entries: BTreeMap<CompactString, LegacyEntry>,
+    }
+
+    fn legacy(entry: &Entry) -> LegacyEntry {
+        match entry {
+            Entry::File(info) => LegacyEntry::File(*info),
+            Entry::Dir(dir) => LegacyEntry::Dir(LegacyDir {
+                info: dir.info,
+                dirs: dir.dirs,
+                files: dir.files,
+                entries: dir
+                    .entries
+                    .iter()
+                    .map(|(file_name, entry)| (file_name.clone(), legacy(entry)))
+                    .collect(),
+            }),
+        }
+    }
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir =
+            std::env::temp_dir().join(format!("ssdedupe-catalog-{name}-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn catalog(root: Option<PathBuf>, compression: Compression) -> Catalog {
+        let entry = dir([("a", dir([("x", file(1)), ("y", file(2))])), ("b", file(3))]);
+        Catalog::new(root, ScanOptions::default(), compression, Tree::new(entry))
+    }
+
+    fn tree_bytes(catalog: &Catalog) -> Vec<u8> {
+        postcard::to_allocvec(catalog.tree().unwrap()).unwrap()
+    }
+
+    #[test]
+    fn open_reads_only_the_header() {
+        let dir = temp_dir("header");
+        let path = dir.join("drive.fsinfo");
+        // longer than what is read at first
+        let root = PathBuf::from("long/".repeat(2000));
+        let saved = catalog(Some(root.clone()), Compression::Zstd);
+        saved.save(&path).unwrap();
+
+        let mut opened = Catalog::open(&path).unwrap();
+        assert!(opened.tree().is_none());
+        assert_eq!(opened.root, Some(root));
+        assert_eq!(opened.compression, Compression::Zstd);
+        assert_eq!(opened.summary().files, 3);
+
+        opened.load_tree(&path).unwrap();
+        assert_eq!(tree_bytes(&opened), tree_bytes(&saved));
+        assert_eq!(
+            postcard::to_allocvec(&Catalog::read_tree(&path).unwrap()).unwrap(),
+            tree_bytes(&saved)
+        );
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn bare_entries_and_current_catalogs_can_be_opened() {
+        let entry = dir([("a", dir([("x", file(1)), ("y", file(2))])), ("b", file(3))]);
+        let tree = Tree::new(entry.clone());
+        let tree_data = postcard::to_allocvec(&tree).unwrap();
+        let root = Some(PathBuf::from("/mnt/drive"));
+        let options = ScanOptions {
+            count: true,
+            archives: true,
+            dir_hashing: DirHashing::Names,
+        };
+
+        let dir = temp_dir("versions");
+        let bare_path = dir.join("bare.fsinfo");
+        fs::write(&bare_path, postcard::to_allocvec(&legacy(&entry)).unwrap()).unwrap();
+        let mut bare = Catalog::open(&bare_path).unwrap();
+        // the tree is all there is, so it is read right away
+        assert!(bare.tree().is_some());
+        assert_eq!(bare.root, None);
+        assert_eq!(bare.options.dir_hashing, legacy::OPTIONS.dir_hashing);
+        assert_eq!(bare.summary().files, 3);
+        bare.load_tree(&bare_path).unwrap();
+        assert_eq!(tree_bytes(&bare), tree_data);
+
+        let path = dir.join("current.fsinfo");
+        Catalog::new(root.clone(), options, Compression::Zstd, tree)
+            .save(&path)
+            .unwrap();
+        let mut current = Catalog::open(&path).unwrap();
+        assert!(current.tree().is_none());
+        assert_eq!(current.root, root);
+        assert_eq!(current.options.dir_hashing, DirHashing::Names);
+        assert!(current.options.count && current.options.archives);
+        assert_eq!(current.summary().files, 3);
+        current.load_tree(&path).unwrap();
+        assert_eq!(tree_bytes(&current), tree_data);
+
+        let path = dir.join("unsupported.fsinfo");
+        let data = postcard::to_extend(&(VERSION + 1), MAGIC.to_vec()).unwrap();
+        fs::write(&path, data).unwrap();
+        assert!(matches!(
+            Catalog::open(&path),
+            Err(CatalogError::UnsupportedVersion(version)) if version == VERSION + 1
+        ));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn compressed_and_uncompressed_catalogs_load_the_same() {
+        // the same files in every folder, like in many copies of a project
+        let entry = dir((0..100).map(|index| {
+            let files =
+                (0..10).map(|file_index| (format!("file {file_index}.txt"), file(file_index)));
+            (format!("folder {index}"), dir(files))
+        }));
+        let dir = temp_dir("compression");
+        let path = dir.join("drive.fsinfo");
+        let saved = Catalog::new(
+            None,
+            ScanOptions::default(),
+            Compression::None,
+            Tree::new(entry),
+        );
+        saved.save(&path).unwrap();
+        let uncompressed_len = fs::metadata(&path).unwrap().len();
+
+        // rewriting an opened catalog compressed, like the toggle in a drive's row
+        let mut opened = Catalog::open(&path).unwrap();
+        assert_eq!(opened.compression, Compression::None);
+        opened.load_tree(&path).unwrap();
+        opened.compression = Compression::Zstd;
+        opened.save(&path).unwrap();
+        assert!(fs::metadata(&path).unwrap().len() < uncompressed_len);
+
+        let mut reopened = Catalog::open(&path).unwrap();
+        assert_eq!(reopened.compression, Compression::Zstd);
+        reopened.load_tree(&path).unwrap();
+        assert_eq!(tree_bytes(&reopened), tree_bytes(&saved));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn truncated_catalog_is_noticed_when_opened() {
+        let dir = temp_dir("truncated");
+        let path = dir.join("drive.fsinfo");
+        let previous = catalog(None, Compression::Zstd);
+        previous.save(&path).unwrap();
+        let mut current = previous.clone();
+        current.set_tree(Tree::new(file(4)));
+        current.save(&path).unwrap();
+
+        let len = fs::metadata(&path).unwrap().len();
+        File::options()
+            .write(true)
+            .open(&path)
+            .unwrap()
+            .set_len(len - 1)
+            .unwrap();
+        assert!(matches!(
+            Catalog::open(&path),
+            Err(CatalogError::LengthMismatch)
+        ));
+        assert!(matches!(
+            Catalog::read_tree(&path),
+            Err(CatalogError::LengthMismatch)
+        ));
+
+        let backup = Catalog::open_backup(&path).unwrap();
+        assert_eq!(tree_bytes(&backup), tree_bytes(&previous));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn empty_updates_leave_a_shared_tree_alone() {
+        let mut catalog = catalog(None, Compression::None);
+        let shared = catalog.tree().unwrap().clone();
+        assert!(!catalog.update_tree(Vec::new()));
+        assert!(Arc::ptr_eq(catalog.tree().unwrap(), &shared));
+
+        assert!(catalog.update_tree(vec![("c".into(), Some(file(4)))]));
+        assert!(!Arc::ptr_eq(catalog.tree().unwrap(), &shared));
+        assert_eq!(catalog.summary().files, 4);
+        assert_eq!(shared.files(), 3);
+    }
+
+    #[test]
+    fn corrupted_tree_fails_its_checksum() {
+        let dir = temp_dir("checksum");
+        let path = dir.join("drive.fsinfo");
+        catalog(None, Compression::None).save(&path).unwrap();
+
+        let mut data = fs::read(&path).unwrap();
+        *data.last_mut().unwrap() ^= 1;
+        fs::write(&path, data).unwrap();
+        assert!(Catalog::open(&path).is_ok());
+        assert!(matches!(
+            Catalog::read_tree(&path),
+            Err(CatalogError::ChecksumMismatch)
+        ));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,197 @@
+use std::{
+    collections::{HashMap, HashSet},
+    path::PathBuf,
+};
+
+use crate::{scan::EntryInfo, tree::EntryRef};
+
+/// How files of two directories are paired up.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Matching {
+    /// Files only match if they have the same relative path.
+    Path,
+    /// Files match if they have the same content, even if they were moved or renamed.
+    Content,
+}
+
+/// A comparison of all files in two directories (or two files).
+///
+/// Paths are relative to the compared directories.
+#[derive(Clone, Debug, Default)]
+pub struct Diff {
+    pub only_left: Vec<(PathBuf, EntryInfo)>,
+    pub only_right: Vec<(PathBuf, EntryInfo)>,
+    /// Files with the same content by their path on the left and on the right.
+    pub identical: Vec<(PathBuf, PathBuf, EntryInfo)>,
+    /// Files with the same path, but different content on the left and on the right.
+    pub changed: Vec<(PathBuf, EntryInfo, EntryInfo)>,
+}
+
+impl Diff {
+    /// Compares `left` and `right` using only their stored hashes, so neither has to be available.
+    pub fn new(left: EntryRef, right: EntryRef, matching: Matching) -> Self {
+        let files = |entry: EntryRef| {
+            entry
+                .file_paths()
+                .map(|(info, path)| (path, info))
+                .collect::<Vec<_>>()
+        };
+        let left = files(left);
+        let right = files(right);
+        let left_by_path = left
+            .iter()
+            .map(|(path, info)| (path.as_path(), *info))
+            .collect::<HashMap<_, _>>();
+        let right_by_path = right
+            .iter()
+            .map(|(path, info)| (path.as_path(), *info))
+            .collect::<HashMap<_, _>>();
+
+        let mut diff = Self::default();
+        match matching {
+            Matching::Path => {
+                for (path, info) in &left {
+                    match right_by_path.get(path.as_path()) {
+                        Some(right_info) if right_info == info => {
+                            diff.identical.push((path.clone(), path.clone(), *info));
+                        }
+                        Some(right_info) => diff.changed.push((path.clone(), *info, *right_info)),
+                        None => diff.only_left.push((path.clone(), *info)),
+                    }
+                }
+
+                diff.only_right = right
+                    .into_iter()
+                    .filter(|(path, _)| !left_by_path.contains_key(path.as_path()))
+                    .collect();
+            }
+            Matching::Content => {
+                let left_contents = left.iter().map(|(_, info)| *info).collect::<HashSet<_>>();
+                let mut right_by_content = HashMap::new();
+                for (path, info) in &right {
+                    right_by_content.entry(*info).or_insert(path.as_path());
+                }
+
+                for (path, info) in &left {
+                    let right_info = right_by_path.get(path.as_path());
+                    if right_info == Some(info) {
+                        diff.identical.push((path.clone(), path.clone(), *info));
+                    } else if let Some(right_path) = right_by_content.get(info) {
+                        diff.identical
+                            .push((path.clone(), right_path.to_path_buf(), *info));
+                    } else if let Some(right_info) = right_info
+                        && !left_contents.contains(right_info)
+                    {
+                        diff.changed.push((path.clone(), *info, *right_info));
+                    } else {
+                        diff.only_left.push((path.clone(), *info));
+                    }
+                }
+
+                diff.only_right = right
+                    .iter()
+                    .filter(|(path, info)| {
+                        // files that were changed are already listed on the left
+                        !left_contents.contains(info)
+                            && left_by_path
+                                .get(path.as_path())
+                                .is_none_or(|left_info| right_by_content.contains_key(left_info))
+                    })
+                    .cloned()
+                    .collect();
+            }
+        }
+
+        diff
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        scan::{
+            Entry,
+            tests::{dir, file},
+        },
+        tree::Tree,
+    };
+
+    fn diff(left: Entry, right: Entry, matching: Matching) -> Diff {
+        Diff::new(Tree::new(left).root(), Tree::new(right).root(), matching)
+    }
+
+    fn paths(files: &[(PathBuf, EntryInfo)]) -> Vec<&str> {
+        files
+            .iter()
+            .map(|(path, _)| path.to_str().unwrap())
+            .collect()
+    }
+
+    fn identical(diff: &Diff) -> Vec<(&str, &str)> {
+        diff.identical
+            .iter()
+            .map(|(left, right, _)| (left.to_str().unwrap(), right.to_str().unwrap()))
+            .collect()
+    }
+
+    #[test]
+    fn path_matching_pairs_files_by_path() {
+        let diff = diff(
+            dir([("a", file(1)), ("b", file(2)), ("c", file(3))]),
+            dir([("a", file(1)), ("b", file(4)), ("d", file(5))]),
+            Matching::Path,
+        );
+        assert_eq!(identical(&diff), [("a", "a")]);
+        let [(path, left, right)] = &diff.changed[..] else {
+            panic!("only b should have changed");
+        };
+        assert_eq!(
+            (path.to_str().unwrap(), left.bytes, right.bytes),
+            ("b", 2, 4)
+        );
+        assert_eq!(paths(&diff.only_left), ["c"]);
+        assert_eq!(paths(&diff.only_right), ["d"]);
+    }
+
+    #[test]
+    fn content_matching_follows_renamed_files() {
+        let diff = diff(
+            dir([
+                ("a", file(1)),
+                ("c", file(3)),
+                ("sub", dir([("b", file(2))])),
+            ]),
+            dir([
+                ("c", file(6)),
+                ("e", file(7)),
+                ("renamed", file(1)),
+                ("sub", dir([("b", file(2))])),
+            ]),
+            Matching::Content,
+        );
+        assert_eq!(identical(&diff), [("a", "renamed"), ("sub/b", "sub/b")]);
+        let [(path, left, right)] = &diff.changed[..] else {
+            panic!("only c should have changed");
+        };
+        assert_eq!(
+            (path.to_str().unwrap(), left.bytes, right.bytes),
+            ("c", 3, 6)
+        );
+        assert!(diff.only_left.is_empty());
+        assert_eq!(paths(&diff.only_right), ["e"]);
+    }
+
+    #[test]
+    fn content_matching_prefers_moved_content_over_changes() {
+        let diff = diff(
+            dir([("a", file(1)), ("b", file(2))]),
+            dir([("a", file(2)), ("c", file(1))]),
+            Matching::Content,
+        );
+        assert_eq!(identical(&diff), [("a", "c"), ("b", "a")]);
+        assert!(diff.changed.is_empty());
+        assert!(diff.only_left.is_empty());
+        assert!(diff.only_right.is_empty());
+    }
+}
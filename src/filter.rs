@@ -0,0 +1,366 @@
+use std::{collections::BTreeMap, path::Path};
+
+use globset::{GlobBuilder, GlobMatcher};
+use itertools::Itertools;
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    scan::{EntryInfo, EntryKind},
+    tree::{NodeId, Roots},
+};
+
+/// Filters for the duplicate list, which are applied before duplicates are filtered by prefix, so
+/// that e.g. files in duplicate directories that are filtered out still show up.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct DuplicateFilters {
+    pub drives: DriveFilter,
+    pub kind: KindFilter,
+    pub min_bytes: u64,
+    /// `0` for unlimited.
+    pub max_bytes: u64,
+    /// Whether to hide empty files and directories.
+    pub hide_empty: bool,
+    /// Extensions separated by commas or spaces; if any are given, only files with one of them are
+    /// shown.
+    pub include_extensions: String,
+    /// Extensions separated by commas or spaces, which are never shown.
+    pub exclude_extensions: String,
+}
+
+impl DuplicateFilters {
+    /// Applies the filters to the [`Entry::unfiltered_duplicates`](crate::scan::Entry) of `roots`
+    /// with one root per drive.
+    ///
+    /// See [`DriveFilter::apply`] for how to use the result.
+    pub fn apply(
+        &self,
+        roots: &Roots,
+        mut duplicates: BTreeMap<EntryInfo, Vec<NodeId>>,
+    ) -> Vec<BTreeMap<EntryInfo, Vec<NodeId>>> {
+        let include_extensions = parse_extensions(&self.include_extensions);
+        let exclude_extensions = parse_extensions(&self.exclude_extensions);
+
+        duplicates.retain(|info, ids| {
+            if !self.kind.matches(info.kind)
+                || info.bytes < self.min_bytes
+                || (self.max_bytes > 0 && info.bytes > self.max_bytes)
+                || (self.hide_empty && info.bytes == 0)
+            {
+                return false;
+            }
+
+            if include_extensions.is_empty() && exclude_extensions.is_empty() {
+                return true;
+            }
+
+            if info.kind == EntryKind::Dir {
+                // directories don't have extensions
+                return include_extensions.is_empty();
+            }
+
+            ids.retain(|&id| {
+                let extension = Path::new(roots.file_name(id))
+                    .extension()
+                    .map(|extension| extension.to_string_lossy().to_lowercase());
+                let matches = |extensions: &[String]| {
+                    extension
+                        .as_ref()
+                        .is_some_and(|extension| extensions.contains(extension))
+                };
+                (include_extensions.is_empty() || matches(&include_extensions))
+                    && !matches(&exclude_extensions)
+            });
+            ids.len() > 1
+        });
+
+        self.drives.apply(duplicates)
+    }
+}
+
+/// Which kind of entries to show.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum KindFilter {
+    #[default]
+    All,
+    Files,
+    Dirs,
+}
+
+impl KindFilter {
+    fn matches(self, kind: EntryKind) -> bool {
+        match self {
+            Self::All => true,
+            Self::Files => kind == EntryKind::File,
+            Self::Dirs => kind == EntryKind::Dir,
+        }
+    }
+}
+
+/// Which duplicates to show, depending on the drives they are on.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum DriveFilter {
+    #[default]
+    All,
+    /// Only copies on the same drive, which are usually waste.
+    WithinDrive,
+    /// Only duplicates that span multiple drives, which are often intentional backups.
+    AcrossDrives,
+}
+
+impl DriveFilter {
+    /// Applies the filter to the [`Entry::unfiltered_duplicates`](crate::scan::Entry) of
+    /// [`Roots`] with one root per drive.
+    ///
+    /// Returns independent sets of duplicates, since the same entry can have copies on multiple
+    /// drives. Each of them can be passed to [`Roots::filter_duplicates_by_prefix`] on its own.
+    pub fn apply(
+        self,
+        mut duplicates: BTreeMap<EntryInfo, Vec<NodeId>>,
+    ) -> Vec<BTreeMap<EntryInfo, Vec<NodeId>>> {
+        match self {
+            Self::All => vec![duplicates],
+            Self::WithinDrive => {
+                let mut drives = BTreeMap::<_, BTreeMap<_, _>>::new();
+                for (info, ids) in duplicates {
+                    for (drive, ids) in ids.into_iter().into_group_map_by(|id| id.root()) {
+                        if ids.len() > 1 {
+                            drives.entry(drive).or_default().insert(info, ids);
+                        }
+                    }
+                }
+                drives.into_values().collect()
+            }
+            Self::AcrossDrives => {
+                duplicates
+                    .retain(|_, ids| ids.iter().map(|id| id.root()).all_equal_value().is_err());
+                vec![duplicates]
+            }
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum SearchMode {
+    /// Case-insensitive substring.
+    #[default]
+    Substring,
+    /// Case-insensitive glob, where `*` also matches `/`, e.g. `*/photos/*.jpg`.
+    Glob,
+    Regex,
+}
+
+/// Matches paths against a search query.
+pub enum PathSearch {
+    Substring(String),
+    Glob(GlobMatcher),
+    Regex(Regex),
+}
+
+impl PathSearch {
+    /// Returns an error message if the query is not a valid glob or regex.
+    pub fn new(query: &str, mode: SearchMode) -> Result<Self, String> {
+        Ok(match mode {
+            SearchMode::Substring => Self::Substring(query.to_lowercase()),
+            SearchMode::Glob => Self::Glob(
+                GlobBuilder::new(query)
+                    .case_insensitive(true)
+                    .build()
+                    .map_err(|error| error.to_string())?
+                    .compile_matcher(),
+            ),
+            SearchMode::Regex => Self::Regex(Regex::new(query).map_err(|error| error.to_string())?),
+        })
+    }
+
+    pub fn matches(&self, path: &Path) -> bool {
+        match self {
+            Self::Substring(query) => path.to_string_lossy().to_lowercase().contains(query),
+            Self::Glob(glob) => glob.is_match(path),
+            Self::Regex(regex) => regex.is_match(&path.to_string_lossy()),
+        }
+    }
+}
+
+/// Parses a list of extensions like `jpg, .PNG txt` into lowercase extensions without dots.
+fn parse_extensions(extensions: &str) -> Vec<String> {
+    extensions
+        .split([',', ' '])
+        .map(|extension| extension.trim().trim_start_matches('.').to_lowercase())
+        .filter(|extension| !extension.is_empty())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::AtomicBool;
+
+    use super::*;
+    use crate::scan::{
+        Entry,
+        tests::{dir, file, roots},
+    };
+
+    /// Returns each independent group of duplicates as a sorted list of sets of paths.
+    fn paths(roots: &Roots, groups: Vec<BTreeMap<EntryInfo, Vec<NodeId>>>) -> Vec<Vec<String>> {
+        groups
+            .into_iter()
+            .map(|duplicates| {
+                let mut sets = duplicates
+                    .into_values()
+                    .map(|ids| {
+                        ids.into_iter()
+                            .map(|id| roots.path(id).to_string_lossy().into_owned())
+                            .sorted()
+                            .join("; ")
+                    })
+                    .collect::<Vec<_>>();
+                sets.sort_unstable();
+                sets
+            })
+            .collect()
+    }
+
+    fn duplicates(roots: &Roots) -> BTreeMap<EntryInfo, Vec<NodeId>> {
+        Entry::unfiltered_duplicates(roots.grouped(&AtomicBool::new(false)).unwrap())
+    }
+
+    #[test]
+    fn drive_filter_splits_or_drops_duplicates_by_drive() {
+        let roots = roots([
+            (
+                "a",
+                dir([
+                    ("1", file(1)),
+                    ("copy", file(1)),
+                    ("4", file(4)),
+                    ("copy 4", file(4)),
+                ]),
+            ),
+            ("b", dir([("1", file(1)), ("3", file(3))])),
+            ("c", dir([("3", file(3)), ("copy", file(3))])),
+        ]);
+        let filter = |filter: DriveFilter| paths(&roots, filter.apply(duplicates(&roots)));
+
+        assert_eq!(
+            filter(DriveFilter::All),
+            [["a/1; a/copy; b/1", "a/4; a/copy 4", "b/3; c/3; c/copy"]]
+        );
+        assert_eq!(
+            filter(DriveFilter::WithinDrive),
+            [vec!["a/1; a/copy", "a/4; a/copy 4"], vec!["c/3; c/copy"]]
+        );
+        assert_eq!(
+            filter(DriveFilter::AcrossDrives),
+            [["a/1; a/copy; b/1", "b/3; c/3; c/copy"]]
+        );
+    }
+
+    #[test]
+    fn duplicate_filters_check_kind_size_and_extensions() {
+        let roots = roots([
+            (
+                "a",
+                dir([
+                    ("photo.JPG", file(10)),
+                    ("notes.txt", file(5)),
+                    ("empty", file(0)),
+                    ("sub", dir([("z.jpg", file(7))])),
+                ]),
+            ),
+            (
+                "b",
+                dir([
+                    ("photo.jpg", file(10)),
+                    ("notes.txt", file(5)),
+                    ("empty.txt", file(0)),
+                    ("sub", dir([("z.jpg", file(7))])),
+                ]),
+            ),
+        ]);
+        let filter = |filters: DuplicateFilters| {
+            paths(&roots, filters.apply(&roots, duplicates(&roots)))
+                .into_iter()
+                .flatten()
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(filter(DuplicateFilters::default()).len(), 6);
+        assert_eq!(
+            filter(DuplicateFilters {
+                kind: KindFilter::Dirs,
+                ..Default::default()
+            }),
+            ["a/sub; b/sub", "a; b"]
+        );
+        assert_eq!(
+            filter(DuplicateFilters {
+                kind: KindFilter::Files,
+                min_bytes: 1,
+                max_bytes: 7,
+                ..Default::default()
+            }),
+            ["a/notes.txt; b/notes.txt", "a/sub/z.jpg; b/sub/z.jpg"]
+        );
+        assert_eq!(
+            filter(DuplicateFilters {
+                hide_empty: true,
+                ..Default::default()
+            })
+            .len(),
+            5
+        );
+        // directories have no extension, so they are only shown without included extensions
+        assert_eq!(
+            filter(DuplicateFilters {
+                include_extensions: ".JPG, png".into(),
+                ..Default::default()
+            }),
+            ["a/photo.JPG; b/photo.jpg", "a/sub/z.jpg; b/sub/z.jpg"]
+        );
+        // only one of the empty files is left, which is no duplicate anymore
+        assert_eq!(
+            filter(DuplicateFilters {
+                kind: KindFilter::Files,
+                exclude_extensions: "txt".into(),
+                ..Default::default()
+            }),
+            ["a/photo.JPG; b/photo.jpg", "a/sub/z.jpg; b/sub/z.jpg"]
+        );
+    }
+
+    #[test]
+    fn path_search_supports_substrings_globs_and_regexes() {
+        let matches = |query: &str, mode: SearchMode, path: &str| {
+            PathSearch::new(query, mode)
+                .unwrap()
+                .matches(Path::new(path))
+        };
+
+        assert!(matches("PHOTOS", SearchMode::Substring, "a/Photos/x.jpg"));
+        assert!(!matches("videos", SearchMode::Substring, "a/Photos/x.jpg"));
+
+        // `*` matches across folders and case doesn't matter
+        assert!(matches(
+            "*/photos/*.jpg",
+            SearchMode::Glob,
+            "a/b/Photos/2024/x.JPG"
+        ));
+        assert!(!matches(
+            "*/photos/*.jpg",
+            SearchMode::Glob,
+            "a/photos/x.png"
+        ));
+
+        assert!(matches(r"^a/.*\.jpg$", SearchMode::Regex, "a/Photos/x.jpg"));
+        assert!(!matches(
+            r"^a/.*\.jpg$",
+            SearchMode::Regex,
+            "b/Photos/x.jpg"
+        ));
+
+        assert!(PathSearch::new("[", SearchMode::Glob).is_err());
+        assert!(PathSearch::new("(", SearchMode::Regex).is_err());
+    }
+}
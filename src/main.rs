@@ -1,23 +1,45 @@
+mod analysis;
+mod catalog;
+mod diff;
+mod filter;
+mod overlap;
+mod report;
 mod scan;
+mod scheduler;
+mod tree;
 mod utils;
+mod watch;
 
 use std::{
+    collections::{BTreeSet, HashSet, VecDeque},
     convert::identity,
-    fs,
+    fs, io, iter,
+    num::{NonZeroU64, NonZeroUsize},
     path::{Path, PathBuf},
     sync::Arc,
     thread::{self, JoinHandle, available_parallelism},
+    time::Duration,
 };
 
 use eframe::storage_dir;
 use egui::{
-    CentralPanel, CollapsingHeader, Grid, NumExt, ScrollArea, TextEdit, TopBottomPanel, Ui, vec2,
+    CentralPanel, CollapsingHeader, ComboBox, DragValue, Grid, Modal, NumExt, ProgressBar,
+    ScrollArea, Slider, TextEdit, TextStyle, TopBottomPanel, Ui, ViewportCommand, vec2,
 };
 use humansize::{BINARY, FormatSize, FormatSizeOptions};
 
 use crate::{
-    scan::{Entry, EntryKind, ScanState},
+    analysis::{Analysis, AnalysisJob, Duplicate},
+    catalog::{Catalog, CatalogError, Compression},
+    diff::{Diff, Matching},
+    filter::{DriveFilter, DuplicateFilters, KindFilter, PathSearch, SearchMode},
+    overlap::NearDuplicate,
+    report::{Coverage, Redundancy, UniqueContent},
+    scan::{DirHashing, Entry, EntryInfo, EntryKind, ScanOptions, ScanState},
+    scheduler::Scheduler,
+    tree::{Roots, Tree},
     utils::TryJoin,
+    watch::Watch,
 };
 
 const APP_NAME: &str = "SSDeDupe";
@@ -25,8 +47,15 @@ const DRIVE_EXTENSION: &str = ".fsinfo";
 
 const SIZE_FORMAT: FormatSizeOptions = BINARY;
 
+const DUPLICATE_FILTERS_KEY: &str = "duplicate_filters";
+
+const DIR_HASHING_HINT: &str = "Only consider folders duplicates if the names of all files and \
+    folders inside them match as well";
+const COMPRESSION_HINT: &str = "Store the catalog compressed, which makes it a lot smaller, but a bit \
+    slower to load";
+
 fn main() -> eframe::Result {
-    // keep one thread for UI
+    // keep one thread for UI; scans don't use this pool, since they get one per device instead
     rayon::ThreadPoolBuilder::new()
         .num_threads((available_parallelism().unwrap().get() - 1).at_least(1))
         .build_global()
@@ -40,29 +69,84 @@ fn main() -> eframe::Result {
     let mut drives = drives_dir
         .read_dir()
         .unwrap()
-        .map(|dir_entry| {
-            let dir_entry = dir_entry.unwrap();
+        .filter_map(|dir_entry| {
+            // skips unreadable entries, backups, catalogs that are being written and stray files
+            let dir_entry = dir_entry.ok()?;
             let name = dir_entry
-                .path()
                 .file_name()
-                .unwrap()
-                .to_str()
-                .unwrap()
-                .strip_suffix(DRIVE_EXTENSION)
-                .unwrap()
+                .to_str()?
+                .strip_suffix(DRIVE_EXTENSION)?
                 .to_string();
-            Drive::new(name, DriveState::load(&dir_entry.path()))
+            Some(Drive::new(name, DriveState::load(&dir_entry.path())))
         })
         .collect::<Vec<_>>();
 
     let drive_path = move |name: &str| drives_dir.join(format!("{name}{DRIVE_EXTENSION}"));
 
+    let scheduler = Scheduler::default();
+    let mut scan_options = ScanOptions::default();
+    let mut compression = Compression::default();
     let mut select_drive = None;
     let mut update_duplicates = false;
-    let mut redundant_bytes = 0;
-    let mut duplicates = Vec::new();
+    let mut analysis = Analysis::default();
+    let mut analysis_job = None::<AnalysisJob>;
+    // loaded from eframe's storage in the first frame
+    let mut duplicate_filters = None::<DuplicateFilters>;
+    let mut search = DuplicateSearch::default();
+    let mut duplicate_list = DuplicateList::default();
+    let mut view = View::Duplicates;
+    let mut near_duplicates = NearDuplicatesView::default();
+    let mut diff = DiffView::default();
+    let mut coverage = CoverageView::default();
+    let mut unique = UniqueView::default();
+    let mut redundancy = RedundancyView::default();
+    // whether closing waits for catalogs to be saved
+    let mut closing = false;
+
+    eframe::run_simple_native(APP_NAME, Default::default(), move |ctx, frame| {
+        let duplicate_filters = duplicate_filters.get_or_insert_with(|| {
+            frame
+                .storage()
+                .and_then(|storage| eframe::get_value(storage, DUPLICATE_FILTERS_KEY))
+                .unwrap_or_default()
+        });
+
+        // unfinished scans are kept as partial catalogs, so that they can be resumed after a
+        // restart, watches save what changed before they stop and jobs may still save catalogs,
+        // so closing waits for all of them
+        let busy = |drive: &Drive| drive.state.is_scanning() || drive.state.is_saving();
+        if ctx.input(|input| input.viewport().close_requested()) && drives.iter().any(busy) {
+            ctx.send_viewport_cmd(ViewportCommand::CancelClose);
+            closing = true;
+        }
+
+        if closing {
+            for drive in &mut drives {
+                match &mut drive.state {
+                    DriveState::Scanning { state, .. } => state.cancel(),
+                    DriveState::Done { watch, pending, .. } => {
+                        pending.clear();
+                        if let Some(watch) = watch {
+                            watch.stop();
+                        }
+                    }
+                    DriveState::Broken { .. } => {}
+                }
+            }
+
+            if drives.iter().any(busy) {
+                Modal::new("closing".into()).show(ctx, |ui| {
+                    ui.horizontal(|ui| {
+                        ui.spinner();
+                        ui.label("Saving scans and catalogs before closing...");
+                    });
+                });
+            } else {
+                closing = false;
+                ctx.send_viewport_cmd(ViewportCommand::Close);
+            }
+        }
 
-    eframe::run_simple_native(APP_NAME, Default::default(), move |ctx, _frame| {
         TopBottomPanel::top("drives")
             .resizable(true)
             .min_height(0.0)
@@ -84,6 +168,41 @@ fn main() -> eframe::Result {
                             folder
                         }));
                     }
+
+                    ui.checkbox(&mut scan_options.count, "Count files first")
+                        .on_hover_text(
+                            "Quickly count all files before scanning them, so that the progress \
+                            and remaining time can be shown",
+                        );
+                    ui.checkbox(&mut scan_options.archives, "Scan inside archives")
+                        .on_hover_text(
+                            "Scan zip, tar and tar.gz files as if they were folders, so that \
+                            their contents can match other files",
+                        );
+                    let mut names = scan_options.dir_hashing == DirHashing::Names;
+                    if ui
+                        .checkbox(&mut names, "Compare names")
+                        .on_hover_text(DIR_HASHING_HINT)
+                        .changed()
+                    {
+                        scan_options.dir_hashing = if names {
+                            DirHashing::Names
+                        } else {
+                            DirHashing::Content
+                        };
+                    }
+                    let mut compress = compression == Compression::Zstd;
+                    if ui
+                        .checkbox(&mut compress, "Compress catalog")
+                        .on_hover_text(COMPRESSION_HINT)
+                        .changed()
+                    {
+                        compression = if compress {
+                            Compression::Zstd
+                        } else {
+                            Compression::None
+                        };
+                    }
                 });
 
                 if let Some(selected_drive) = select_drive.try_join() {
@@ -91,7 +210,7 @@ fn main() -> eframe::Result {
                         drives.push(Drive::new(
                             path.file_name()
                                 .map_or_else(|| "new drive".into(), |x| x.to_string_lossy().into()),
-                            DriveState::scan(path),
+                            DriveState::scan(path, scan_options, compression, &scheduler),
                         ));
                     } else {
                         // user cancelled the dialog
@@ -107,13 +226,28 @@ fn main() -> eframe::Result {
                             drives.retain_mut(|drive| {
                                 match &drive.state {
                                     DriveState::Scanning { state, .. } => {
-                                        if ui.button("❌").clicked() {
+                                        if ui
+                                            .button("❌")
+                                            .on_hover_text(
+                                                "Cancel scan, keeping what was scanned so far",
+                                            )
+                                            .clicked()
+                                        {
                                             state.cancel();
                                         }
                                     }
-                                    DriveState::Done { .. } => {
-                                        if ui.button("🗑").clicked()
-                                            && fs::remove_file(drive_path(&drive.name)).is_ok()
+                                    DriveState::Done { .. } | DriveState::Broken { .. } => {
+                                        if ui
+                                            .add_enabled(
+                                                !drive.state.is_saving(),
+                                                egui::Button::new("🗑"),
+                                            )
+                                            .on_disabled_hover_text(
+                                                "Wait for the catalog to be saved and stop \
+                                                watching it first",
+                                            )
+                                            .clicked()
+                                            && catalog::remove(&drive_path(&drive.name)).is_ok()
                                         {
                                             return false;
                                         }
@@ -122,16 +256,19 @@ fn main() -> eframe::Result {
 
                                 let name_edit = ui.add_sized(
                                     vec2(200.0, ui.spacing().interact_size.y),
-                                    TextEdit::singleline(&mut drive.edit_name),
+                                    // the catalog is saved under its current name in the
+                                    // background
+                                    TextEdit::singleline(&mut drive.edit_name)
+                                        .interactive(!drive.state.is_saving()),
                                 );
                                 if name_edit.lost_focus() && drive.edit_name != drive.name {
-                                    if !drive.edit_name.is_empty() && !drive.state.is_done()
+                                    if !drive.edit_name.is_empty() && drive.state.is_scanning()
                                         || (drive_path(&drive.edit_name)
                                             .try_exists()
                                             .is_ok_and(|exists| !exists)
-                                            && fs::rename(
-                                                drive_path(&drive.name),
-                                                drive_path(&drive.edit_name),
+                                            && catalog::rename(
+                                                &drive_path(&drive.name),
+                                                &drive_path(&drive.edit_name),
                                             )
                                             .is_ok())
                                     {
@@ -141,8 +278,11 @@ fn main() -> eframe::Result {
                                     }
                                 }
 
+                                let mut resume = false;
                                 match &mut drive.state {
-                                    DriveState::Scanning { state, join_handle } => {
+                                    DriveState::Scanning {
+                                        state, join_handle, ..
+                                    } => {
                                         dirs_files_bytes(
                                             ui,
                                             state.bytes(),
@@ -150,7 +290,30 @@ fn main() -> eframe::Result {
                                             state.files(),
                                         );
 
-                                        ui.spinner();
+                                        let mut paused = state.paused();
+                                        if ui
+                                            .toggle_value(&mut paused, "⏸")
+                                            .on_hover_text("Pause scan")
+                                            .changed()
+                                        {
+                                            state.set_paused(paused);
+                                        }
+
+                                        ui.menu_button("⚙", |ui| throttle_settings(ui, state))
+                                            .response
+                                            .on_hover_text("Throttle scan");
+
+                                        if paused {
+                                            ui.label("paused");
+                                        } else if state.sequential() {
+                                            ui.spinner().on_hover_text(
+                                                "Spinning disk; reading files one by one",
+                                            );
+                                        } else {
+                                            ui.spinner();
+                                        }
+
+                                        scan_progress(ui, state);
 
                                         if let Some((error, extra)) = state.last_error_plus() {
                                             ui.colored_label(
@@ -160,41 +323,221 @@ fn main() -> eframe::Result {
                                         }
 
                                         if let Some(new_entry) = join_handle.try_join() {
-                                            let error_log = state.clone_error_log();
-                                            let name = drive.name.clone();
-                                            let mut index = 1;
-                                            while drive_path(&drive.name)
-                                                .try_exists()
-                                                .ok()
-                                                .is_none_or(identity)
-                                            {
-                                                drive.name = format!("{name} ({index})");
-                                                index += 1;
-                                            }
-
+                                            drive.finish_scan(new_entry, &drive_path);
                                             if !name_edit.has_focus() {
                                                 drive.edit_name = drive.name.clone();
                                             }
-
-                                            drive.state = DriveState::save(
-                                                &drive_path(&drive.name),
-                                                new_entry.unwrap_or_default(),
-                                                error_log,
-                                            );
                                         }
                                     }
-                                    DriveState::Done { entry, error_log } => {
-                                        if let Some((entry, enabled)) = entry {
+                                    DriveState::Done {
+                                        catalog,
+                                        watch,
+                                        job,
+                                        pending,
+                                        error_log,
+                                    } => {
+                                        // why the tree couldn't be loaded
+                                        let mut broken = None;
+                                        if let Some((catalog, enabled)) = catalog {
+                                            let path = drive_path(&drive.name);
+
+                                            if let Some(result) = job
+                                                .as_mut()
+                                                .and_then(|job| job.join_handle.try_join())
+                                            {
+                                                *job = None;
+                                                match result.expect("catalog jobs shouldn't panic")
+                                                {
+                                                    JobResult::Loaded(Ok(tree)) => {
+                                                        catalog.set_tree(tree);
+                                                    }
+                                                    // e.g. a catalog whose tree is corrupted
+                                                    JobResult::Loaded(Err(error)) => {
+                                                        broken = Some(error.to_string());
+                                                    }
+                                                    JobResult::Saved(saved, error) => {
+                                                        update_duplicates |= *enabled
+                                                            && saved.options.dir_hashing
+                                                                != catalog.options.dir_hashing;
+                                                        *catalog = saved;
+                                                        error_log.extend(
+                                                            error.map(|error| error.to_string()),
+                                                        );
+                                                    }
+                                                }
+                                            }
+
+                                            if let Some(active) = watch {
+                                                for update in active.updates() {
+                                                    error_log.extend(update.error_log);
+                                                    if let Some(saved) = update.catalog {
+                                                        *catalog = saved;
+                                                        update_duplicates |= *enabled;
+                                                    }
+                                                }
+                                                if active.is_finished() {
+                                                    *watch = None;
+                                                }
+                                            }
+
+                                            let summary = catalog.summary();
                                             dirs_files_bytes(
                                                 ui,
-                                                entry.info().bytes,
-                                                entry.dirs(),
-                                                entry.files(),
+                                                summary.bytes,
+                                                summary.dirs,
+                                                summary.files,
                                             );
 
                                             if ui.checkbox(enabled, "").clicked() {
+                                                if *enabled {
+                                                    pending.push_back(TreeAction::Enable);
+                                                }
                                                 update_duplicates = true;
                                             }
+
+                                            let mut names =
+                                                catalog.options.dir_hashing == DirHashing::Names;
+                                            if ui
+                                                .toggle_value(&mut names, "🔤")
+                                                .on_hover_text(DIR_HASHING_HINT)
+                                                .changed()
+                                            {
+                                                pending.push_back(TreeAction::DirHashing(
+                                                    if names {
+                                                        DirHashing::Names
+                                                    } else {
+                                                        DirHashing::Content
+                                                    },
+                                                ));
+                                            }
+
+                                            let mut compress =
+                                                catalog.compression == Compression::Zstd;
+                                            if ui
+                                                .toggle_value(&mut compress, "🗜")
+                                                .on_hover_text(COMPRESSION_HINT)
+                                                .changed()
+                                            {
+                                                pending.push_back(TreeAction::Compression(
+                                                    if compress {
+                                                        Compression::Zstd
+                                                    } else {
+                                                        Compression::None
+                                                    },
+                                                ));
+                                            }
+
+                                            if catalog.summary().partial
+                                                && ui
+                                                    .add_enabled(
+                                                        catalog.root.is_some(),
+                                                        egui::Button::new("⏵"),
+                                                    )
+                                                    .on_hover_text("Partial scan; resume it")
+                                                    .on_disabled_hover_text(
+                                                        "Partial scan; cannot be resumed, since \
+                                                        its path is unknown",
+                                                    )
+                                                    .clicked()
+                                            {
+                                                pending.push_back(TreeAction::Resume);
+                                            }
+
+                                            if let Some(root) = &catalog.root {
+                                                let mut watching = watch
+                                                    .as_ref()
+                                                    .is_some_and(|watch| !watch.is_stopping());
+                                                if ui
+                                                    .toggle_value(&mut watching, "👁")
+                                                    .on_hover_text(format!(
+                                                        "Watch {} for changes",
+                                                        root.display()
+                                                    ))
+                                                    .changed()
+                                                {
+                                                    if watching {
+                                                        pending.push_back(TreeAction::Watch);
+                                                    } else if let Some(watch) = watch {
+                                                        watch.stop();
+                                                    }
+                                                }
+                                            }
+
+                                            // actions run in order, each once the tree is
+                                            // loaded, no other job works on the catalog and the
+                                            // watch doesn't have its own copy of it anymore
+                                            while broken.is_none()
+                                                && job.is_none()
+                                                && watch
+                                                    .as_ref()
+                                                    .is_none_or(|watch| !watch.is_stopping())
+                                                && let Some(action) = pending.pop_front()
+                                            {
+                                                // trees are only loaded once they are needed
+                                                if catalog.tree().is_none() {
+                                                    pending.push_front(action);
+                                                    *job = Some(CatalogJob::load(
+                                                        path.clone(),
+                                                        ctx.clone(),
+                                                    ));
+                                                    break;
+                                                }
+
+                                                match action {
+                                                    TreeAction::Enable => {
+                                                        update_duplicates |= *enabled;
+                                                    }
+                                                    TreeAction::DirHashing(_)
+                                                    | TreeAction::Compression(_)
+                                                    | TreeAction::Resume
+                                                        if watch.is_some() =>
+                                                    {
+                                                        // the watch saves its copy before it
+                                                        // stops and is restarted afterwards, so
+                                                        // that rescans use the new options
+                                                        if !matches!(action, TreeAction::Resume) {
+                                                            pending.push_front(TreeAction::Watch);
+                                                        }
+                                                        pending.push_front(action);
+                                                        watch.as_mut().unwrap().stop();
+                                                    }
+                                                    TreeAction::DirHashing(dir_hashing) => {
+                                                        *job = Some(CatalogJob::rehash(
+                                                            path.clone(),
+                                                            catalog.clone(),
+                                                            dir_hashing,
+                                                            ctx.clone(),
+                                                        ));
+                                                    }
+                                                    TreeAction::Compression(compression) => {
+                                                        let mut compressed = catalog.clone();
+                                                        compressed.compression = compression;
+                                                        *job = Some(CatalogJob::save(
+                                                            path.clone(),
+                                                            compressed,
+                                                            ctx.clone(),
+                                                        ));
+                                                    }
+                                                    TreeAction::Resume => {
+                                                        resume = true;
+                                                        break;
+                                                    }
+                                                    TreeAction::Watch => {
+                                                        if watch.is_none() {
+                                                            *watch = start_watch(
+                                                                &path, catalog, ctx, error_log,
+                                                            );
+                                                        }
+                                                    }
+                                                }
+                                            }
+
+                                            if let Some(job) = job {
+                                                ui.spinner().on_hover_text(job.description);
+                                            } else if watch.as_ref().is_some_and(Watch::is_stopping)
+                                            {
+                                                ui.spinner().on_hover_text("Saving changes");
+                                            }
                                         }
 
                                         if !error_log.is_empty() {
@@ -205,6 +548,73 @@ fn main() -> eframe::Result {
                                                     }
                                                 });
                                         }
+
+                                        if let Some(error) = broken {
+                                            drive.state = DriveState::Broken {
+                                                error,
+                                                backup_error: None,
+                                            };
+                                        } else if resume
+                                            && let Some((catalog, enabled)) = catalog.take()
+                                        {
+                                            update_duplicates |= enabled;
+                                            drive.state = DriveState::resume(
+                                                catalog,
+                                                scan_options.count,
+                                                &scheduler,
+                                            );
+                                        }
+                                    }
+                                    DriveState::Broken {
+                                        error,
+                                        backup_error,
+                                    } => {
+                                        let path = drive_path(&drive.name);
+                                        if ui
+                                            .button("⟳")
+                                            .on_hover_text("Try to open the catalog again")
+                                            .clicked()
+                                        {
+                                            drive.state = DriveState::load(&path);
+                                        } else if backup_error.is_none()
+                                            && ui
+                                                .add_enabled(
+                                                    catalog::backup_path(&path)
+                                                        .try_exists()
+                                                        .is_ok_and(identity),
+                                                    egui::Button::new("⟲"),
+                                                )
+                                                .on_hover_text(
+                                                    "Recover the previous version of the catalog \
+                                                    from its backup",
+                                                )
+                                                .on_disabled_hover_text("No backup to recover from")
+                                                .clicked()
+                                        {
+                                            match Catalog::open_backup(&path) {
+                                                Ok(catalog) => {
+                                                    drive.state = DriveState::save(
+                                                        &path,
+                                                        Some(catalog),
+                                                        Vec::new(),
+                                                    );
+                                                }
+                                                Err(error) => {
+                                                    *backup_error = Some(error.to_string());
+                                                }
+                                            }
+                                        } else {
+                                            ui.colored_label(
+                                                ui.visuals().error_fg_color,
+                                                format!("Broken catalog: {error}"),
+                                            );
+                                            if let Some(backup_error) = backup_error {
+                                                ui.colored_label(
+                                                    ui.visuals().error_fg_color,
+                                                    format!("Broken backup: {backup_error}"),
+                                                );
+                                            }
+                                        }
                                     }
                                 }
 
@@ -216,64 +626,1052 @@ fn main() -> eframe::Result {
                 });
             });
 
-        if update_duplicates {
-            update_duplicates = false;
+        if let Some(job) = &analysis_job
+            && update_duplicates
+        {
+            job.cancel();
+        }
 
-            let entry = Entry::dir(
+        // a canceled job only stops after its current step, so waiting for it keeps jobs from
+        // piling up while filters are changed quickly
+        if update_duplicates && analysis_job.as_ref().is_none_or(AnalysisJob::is_finished) {
+            update_duplicates = false;
+            analysis_job = Some(AnalysisJob::start(
                 drives
                     .iter()
                     .filter_map(|drive| {
+                        // enabled drives whose tree is still loading are added once it is
                         if let DriveState::Done {
-                            entry: Some((entry, true)),
+                            catalog: Some((catalog, true)),
                             ..
                         } = &drive.state
                         {
-                            Some(((&drive.name).into(), entry.clone()))
+                            Some(((&drive.name).into(), catalog.tree()?.clone()))
                         } else {
                             None
                         }
                     })
                     .collect(),
-            );
-
-            let unfiltered_duplicates = entry.unfiltered_duplicates();
-            redundant_bytes = Entry::redundant_bytes(&unfiltered_duplicates);
-            duplicates = Entry::filter_duplicates_by_prefix(unfiltered_duplicates)
-                .into_iter()
-                .map(|(info, paths)| (info.bytes * (paths.len() as u64 - 1), info, paths))
-                .collect();
-            duplicates.sort_unstable_by_key(|(redundant_bytes, info, paths)| {
-                (*redundant_bytes, info.kind, paths.len())
-            });
+                duplicate_filters.clone(),
+                ctx.clone(),
+            ));
+        }
+
+        if let Some(job) = &mut analysis_job
+            && let Some(finished) = job.try_finish()
+        {
+            analysis = finished;
+            analysis_job = None;
+            search.matches = None;
         }
 
         CentralPanel::default().show(ctx, |ui| {
-            let bytes = redundant_bytes.format_size(SIZE_FORMAT);
-            ui.heading(format!("Duplicates ({bytes} redundant)"));
+            ui.horizontal(|ui| {
+                let bytes = analysis.redundant_bytes.format_size(SIZE_FORMAT);
+                ui.selectable_value(
+                    &mut view,
+                    View::Duplicates,
+                    format!("Duplicates ({bytes} redundant)"),
+                );
+                ui.selectable_value(&mut view, View::NearDuplicates, "Near-Duplicates");
+                ui.selectable_value(&mut view, View::Diff, "Compare");
+                ui.selectable_value(&mut view, View::Coverage, "Backup Coverage");
+                ui.selectable_value(&mut view, View::Unique, "Unique Content");
+                ui.selectable_value(&mut view, View::Redundancy, "Per Drive");
+            });
+
+            if let Some(job) = &analysis_job {
+                let (step, fraction) = job.progress();
+                ui.add(
+                    ProgressBar::new(fraction)
+                        .desired_width(300.0)
+                        .text(format!("Updating: {step}..."))
+                        .animate(true),
+                );
+            }
+            ui.separator();
+
+            match view {
+                View::Duplicates => {
+                    if duplicate_filters_ui(ui, duplicate_filters) {
+                        update_duplicates = true;
+                        ui.ctx().request_repaint();
+                        if let Some(storage) = frame.storage_mut() {
+                            eframe::set_value(storage, DUPLICATE_FILTERS_KEY, duplicate_filters);
+                        }
+                    }
+
+                    search.show(ui);
+
+                    let matches = match search.matches(&analysis.duplicates) {
+                        Ok(matches) => matches,
+                        Err(error) => {
+                            ui.label(error);
+                            return;
+                        }
+                    };
+
+                    if let Some(focus) = duplicate_list.show(ui, &analysis.duplicates, matches) {
+                        search.focus(focus);
+                    }
+                }
+                View::NearDuplicates => {
+                    if let Some((left, right)) = near_duplicates.show(ui, &analysis.drives) {
+                        diff.left = left.to_string_lossy().into();
+                        diff.right = right.to_string_lossy().into();
+                        diff.compare(&analysis.drives);
+                        view = View::Diff;
+                    }
+                }
+                View::Diff => diff.show(ui, &analysis.drives),
+                View::Coverage => coverage.show(ui, &drives, &drive_path),
+                View::Unique => unique.show(ui, &analysis.unique),
+                View::Redundancy => redundancy.show(ui, &analysis.redundancy),
+            }
+        });
+    })
+}
+
+/// Shows the filters of the duplicate list and returns whether they changed.
+fn duplicate_filters_ui(ui: &mut Ui, filters: &mut DuplicateFilters) -> bool {
+    const KIB: u64 = 1024;
 
-            ScrollArea::vertical().show(ui, |ui| {
+    let mut changed = false;
+    ui.horizontal(|ui| {
+        for (filter, text, hover_text) in [
+            (DriveFilter::All, "All", "All duplicates"),
+            (
+                DriveFilter::WithinDrive,
+                "Within one drive",
+                "Only copies on the same drive, which are usually waste",
+            ),
+            (
+                DriveFilter::AcrossDrives,
+                "Across drives",
+                "Only duplicates on multiple drives, which are often backups",
+            ),
+        ] {
+            changed |= ui
+                .selectable_value(&mut filters.drives, filter, text)
+                .on_hover_text(hover_text)
+                .changed();
+        }
+
+        ui.separator();
+
+        for (filter, text) in [
+            (KindFilter::All, "Files and Directories"),
+            (KindFilter::Files, "Files"),
+            (KindFilter::Dirs, "Directories"),
+        ] {
+            changed |= ui
+                .selectable_value(&mut filters.kind, filter, text)
+                .changed();
+        }
+    });
+
+    ui.horizontal(|ui| {
+        ui.label("Size");
+        let mut min_kib = filters.min_bytes / KIB;
+        if ui
+            .add(
+                DragValue::new(&mut min_kib)
+                    .range(0..=u64::MAX / KIB)
+                    .suffix(" KiB"),
+            )
+            .changed()
+        {
+            filters.min_bytes = min_kib * KIB;
+            changed = true;
+        }
+        ui.label("to");
+        let mut max_kib = filters.max_bytes / KIB;
+        if ui
+            .add(
+                DragValue::new(&mut max_kib)
+                    .range(0..=u64::MAX / KIB)
+                    .suffix(" KiB"),
+            )
+            .on_hover_text("0 for unlimited")
+            .changed()
+        {
+            filters.max_bytes = max_kib * KIB;
+            changed = true;
+        }
+
+        changed |= ui.checkbox(&mut filters.hide_empty, "Hide empty").changed();
+
+        ui.separator();
+
+        // only update once editing is done, since every update recalculates all duplicates
+        for (extensions, hint_text) in [
+            (&mut filters.include_extensions, "only extensions"),
+            (&mut filters.exclude_extensions, "exclude extensions"),
+        ] {
+            changed |= ui
+                .add(
+                    TextEdit::singleline(extensions)
+                        .hint_text(hint_text)
+                        .desired_width(120.0),
+                )
+                .on_hover_text("e.g. jpg, png")
+                .lost_focus();
+        }
+    });
+
+    changed
+}
+
+/// Shows a page of duplicates, only rendering the rows that are actually visible.
+#[derive(Default)]
+struct DuplicateList {
+    page: usize,
+    /// Expanded duplicates by their info and first path, which stay the same when the list is
+    /// recalculated.
+    expanded: HashSet<(EntryInfo, PathBuf)>,
+}
+
+impl DuplicateList {
+    const PAGE_SIZE: usize = 1000;
+
+    /// Shows the duplicates with the given indices and returns a folder to focus on if requested.
+    fn show(
+        &mut self,
+        ui: &mut Ui,
+        duplicates: &[Duplicate],
+        indices: &[usize],
+    ) -> Option<PathBuf> {
+        let pages = indices.len().div_ceil(Self::PAGE_SIZE).max(1);
+        self.page = self.page.min(pages - 1);
+
+        if pages > 1 {
+            ui.horizontal(|ui| {
+                if ui
+                    .add_enabled(self.page > 0, egui::Button::new("◀"))
+                    .clicked()
+                {
+                    self.page -= 1;
+                }
+                ui.label(format!("Page {} of {pages}", self.page + 1));
+                if ui
+                    .add_enabled(self.page + 1 < pages, egui::Button::new("▶"))
+                    .clicked()
+                {
+                    self.page += 1;
+                }
+            });
+        }
+
+        let key = |(_, info, paths): &Duplicate| (*info, paths.first().unwrap().clone());
+
+        let page = indices
+            .chunks(Self::PAGE_SIZE)
+            .nth(self.page)
+            .unwrap_or_default();
+        let rows = page
+            .iter()
+            .flat_map(|&index| {
+                let duplicate = &duplicates[index];
+                let paths = self
+                    .expanded
+                    .contains(&key(duplicate))
+                    .then_some(&duplicate.2)
+                    .into_iter()
+                    .flatten();
+                iter::once((index, None)).chain(paths.map(move |path| (index, Some(path))))
+            })
+            .collect::<Vec<_>>();
+
+        let mut focus = None;
+        let mut toggle = None;
+        let row_height = ui.spacing().interact_size.y;
+        ScrollArea::vertical()
+            // keep the scroll position per page
+            .id_salt(self.page)
+            .show_rows(ui, row_height, rows.len(), |ui, range| {
                 ui.set_width(ui.available_width());
-                for (redundant_bytes, info, paths) in duplicates.iter().rev() {
-                    let redundant_bytes = redundant_bytes.format_size(SIZE_FORMAT);
-                    let count = paths.len();
-                    let bytes = info.bytes.format_size(SIZE_FORMAT);
-                    let kind = match info.kind {
-                        EntryKind::Dir => "Directories",
-                        EntryKind::File => "Files",
+                for &(index, path) in &rows[range] {
+                    let duplicate @ (redundant_bytes, info, paths) = &duplicates[index];
+                    let Some(path) = path else {
+                        let redundant_bytes = redundant_bytes.format_size(SIZE_FORMAT);
+                        let count = paths.len();
+                        let bytes = info.bytes.format_size(SIZE_FORMAT);
+                        let kind = match info.kind {
+                            EntryKind::Dir => "Directories",
+                            EntryKind::File => "Files",
+                        };
+                        let expanded = self.expanded.contains(&key(duplicate));
+                        let icon = if expanded { "⏷" } else { "⏵" };
+                        if ui
+                            .selectable_label(
+                                false,
+                                format!(
+                                    "{icon} {redundant_bytes} redundant across {count} {kind} \
+                                    ({bytes} each)"
+                                ),
+                            )
+                            .clicked()
+                        {
+                            toggle = Some(key(duplicate));
+                        }
+                        continue;
                     };
-                    CollapsingHeader::new(format!(
-                        "{redundant_bytes} redundant across {count} {kind} ({bytes} each)"
-                    ))
-                    .id_salt(info)
+
+                    ui.horizontal(|ui| {
+                        ui.add_space(ui.spacing().indent);
+                        ui.label(path.to_string_lossy()).context_menu(|ui| {
+                            if ui.button("Focus on this folder").clicked() {
+                                focus = Some(match info.kind {
+                                    EntryKind::Dir => path.clone(),
+                                    EntryKind::File => path.parent().unwrap_or(path).to_path_buf(),
+                                });
+                            }
+                        });
+                    });
+                }
+            });
+
+        if let Some(key) = toggle
+            && !self.expanded.remove(&key)
+        {
+            self.expanded.insert(key);
+        }
+
+        focus
+    }
+}
+
+/// Narrows down the duplicate list to groups with a matching path.
+#[derive(Default)]
+struct DuplicateSearch {
+    query: String,
+    mode: SearchMode,
+    /// Only show groups with a path inside this folder.
+    focus: Option<PathBuf>,
+    /// The indices of matching duplicates from largest to smallest or why the query is invalid.
+    ///
+    /// `None` if it has to be recalculated.
+    matches: Option<Result<Vec<usize>, String>>,
+}
+
+impl DuplicateSearch {
+    fn show(&mut self, ui: &mut Ui) {
+        ui.horizontal(|ui| {
+            let mut changed = ui
+                .add(TextEdit::singleline(&mut self.query).hint_text("search paths"))
+                .changed();
+            for (mode, text) in [
+                (SearchMode::Substring, "Text"),
+                (SearchMode::Glob, "Glob"),
+                (SearchMode::Regex, "Regex"),
+            ] {
+                changed |= ui.selectable_value(&mut self.mode, mode, text).changed();
+            }
+
+            if let Some(focus) = &self.focus {
+                ui.separator();
+                ui.label(format!("in {}", focus.to_string_lossy()));
+                if ui.button("❌").on_hover_text("Show all folders").clicked() {
+                    self.focus = None;
+                    changed = true;
+                }
+            }
+
+            if changed {
+                self.matches = None;
+            }
+        });
+    }
+
+    fn focus(&mut self, folder: PathBuf) {
+        self.focus = Some(folder);
+        self.matches = None;
+    }
+
+    fn matches(&mut self, duplicates: &[Duplicate]) -> Result<&[usize], &str> {
+        self.matches
+            .get_or_insert_with(|| {
+                let search = (!self.query.is_empty())
+                    .then(|| PathSearch::new(&self.query, self.mode))
+                    .transpose()?;
+                Ok((0..duplicates.len())
+                    .rev()
+                    .filter(|&index| {
+                        duplicates[index].2.iter().any(|path| {
+                            search.as_ref().is_none_or(|search| search.matches(path))
+                                && self
+                                    .focus
+                                    .as_ref()
+                                    .is_none_or(|focus| path.starts_with(focus))
+                        })
+                    })
+                    .collect())
+            })
+            .as_deref()
+            .map_err(String::as_str)
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum View {
+    Duplicates,
+    NearDuplicates,
+    Diff,
+    Coverage,
+    Unique,
+    Redundancy,
+}
+
+struct NearDuplicatesView {
+    min_overlap_percent: u32,
+    /// Searches the enabled drives, which it returns along with the near-duplicates.
+    job: Option<JoinHandle<(Roots, Vec<NearDuplicate>)>>,
+    /// The searched drives and the near-duplicates.
+    results: Option<(Roots, Vec<NearDuplicateRow>)>,
+}
+
+/// A near-duplicate and its differences, which are computed when it is first expanded.
+///
+/// The differences are `None` if either directory doesn't exist anymore.
+type NearDuplicateRow = (NearDuplicate, Option<Option<Diff>>);
+
+impl Default for NearDuplicatesView {
+    fn default() -> Self {
+        Self {
+            min_overlap_percent: 90,
+            job: None,
+            results: None,
+        }
+    }
+}
+
+impl NearDuplicatesView {
+    /// Returns the paths of a near-duplicate if it should be opened in the [`DiffView`].
+    fn show(&mut self, ui: &mut Ui, drives: &Roots) -> Option<(PathBuf, PathBuf)> {
+        if let Some(results) = self.job.try_join() {
+            let (root, near_duplicates) = results.expect("near-duplicate search shouldn't panic");
+            self.results = Some((
+                root,
+                near_duplicates
+                    .into_iter()
+                    .map(|near_duplicate| (near_duplicate, None))
+                    .collect(),
+            ));
+        }
+
+        ui.horizontal(|ui| {
+            ui.add(
+                Slider::new(&mut self.min_overlap_percent, 50..=100)
+                    .suffix("%")
+                    .text("minimum overlap"),
+            )
+            .on_hover_text("Subsets are always included, regardless of their overlap");
+
+            if ui
+                .add_enabled(self.job.is_none(), egui::Button::new("Search"))
+                .on_hover_text(
+                    "Search the enabled drives for folders that mostly contain the same files",
+                )
+                .clicked()
+            {
+                let root = drives.clone();
+                let min_overlap = f64::from(self.min_overlap_percent) / 100.0;
+                let ctx = ui.ctx().clone();
+                self.job = Some(thread::spawn(move || {
+                    let near_duplicates = overlap::near_duplicates(&root, min_overlap);
+                    ctx.request_repaint();
+                    (root, near_duplicates)
+                }));
+            }
+
+            if self.job.is_some() {
+                ui.spinner();
+            }
+        });
+
+        let Some((root, near_duplicates)) = &mut self.results else {
+            return None;
+        };
+
+        if near_duplicates.is_empty() {
+            ui.label("No near-duplicates found");
+            return None;
+        }
+
+        let mut compare = None;
+        ScrollArea::vertical().show(ui, |ui| {
+            ui.set_width(ui.available_width());
+            for (near_duplicate, differences) in near_duplicates {
+                let shared_bytes = near_duplicate.shared_bytes.format_size(SIZE_FORMAT);
+                let smaller = near_duplicate.smaller.to_string_lossy();
+                let larger = near_duplicate.larger.to_string_lossy();
+                let title = if near_duplicate.is_subset() {
+                    format!("{shared_bytes} shared: {smaller} is contained in {larger}")
+                } else {
+                    let percent = near_duplicate.overlap() * 100.0;
+                    format!(
+                        "{shared_bytes} shared: {smaller} and {larger} overlap by {percent:.0}%"
+                    )
+                };
+
+                CollapsingHeader::new(title)
+                    .id_salt((&near_duplicate.smaller, &near_duplicate.larger))
                     .show(ui, |ui| {
-                        for path in paths {
-                            ui.label(path.to_string_lossy());
+                        if ui.button("Compare side by side").clicked() {
+                            compare = Some((
+                                near_duplicate.smaller.clone(),
+                                near_duplicate.larger.clone(),
+                            ));
                         }
+
+                        let differences = differences.get_or_insert_with(|| {
+                            Some(Diff::new(
+                                root.get(&near_duplicate.smaller)?,
+                                root.get(&near_duplicate.larger)?,
+                                Matching::Content,
+                            ))
+                        });
+                        let Some(differences) = differences else {
+                            ui.label("These folders don't exist anymore");
+                            return;
+                        };
+
+                        for (path, files) in [
+                            (&smaller, &differences.only_left),
+                            (&larger, &differences.only_right),
+                        ] {
+                            if files.is_empty() {
+                                ui.label(format!("Nothing only in {path}"));
+                                continue;
+                            }
+
+                            ui.label(format!("Only in {path}:"));
+                            ui.indent(path, |ui| {
+                                for (path, info) in files {
+                                    let bytes = info.bytes.format_size(SIZE_FORMAT);
+                                    ui.label(format!("{} ({bytes})", path.to_string_lossy()));
+                                }
+                            });
+                        }
+
+                        if !differences.changed.is_empty() {
+                            ui.label("Same name, different content:");
+                            ui.indent("changed", |ui| {
+                                for (path, smaller_info, larger_info) in &differences.changed {
+                                    let smaller_bytes = smaller_info.bytes.format_size(SIZE_FORMAT);
+                                    let larger_bytes = larger_info.bytes.format_size(SIZE_FORMAT);
+                                    ui.label(format!(
+                                        "{} ({smaller_bytes} in {smaller}, {larger_bytes} in \
+                                        {larger})",
+                                        path.to_string_lossy()
+                                    ));
+                                }
+                            });
+                        }
+                    });
+            }
+        });
+
+        compare
+    }
+}
+
+struct DiffView {
+    left: String,
+    right: String,
+    matching: Matching,
+    /// The last comparison or why it failed.
+    result: Option<Result<Diff, String>>,
+}
+
+impl Default for DiffView {
+    fn default() -> Self {
+        Self {
+            left: String::new(),
+            right: String::new(),
+            matching: Matching::Path,
+            result: None,
+        }
+    }
+}
+
+impl DiffView {
+    fn compare(&mut self, drives: &Roots) {
+        let get = |path: &str| {
+            drives
+                .get(Path::new(path))
+                .ok_or_else(|| format!("{path} does not exist in the enabled drives"))
+        };
+
+        self.result = Some(
+            get(&self.left).and_then(|left| Ok(Diff::new(left, get(&self.right)?, self.matching))),
+        );
+    }
+
+    fn show(&mut self, ui: &mut Ui, drives: &Roots) {
+        Grid::new("compare").show(ui, |ui| {
+            for (label, path) in [("Left", &mut self.left), ("Right", &mut self.right)] {
+                ui.label(label);
+                ui.add(TextEdit::singleline(path).hint_text("drive/folder"));
+                ui.end_row();
+            }
+
+            ui.label("Match by");
+            ui.horizontal(|ui| {
+                ui.selectable_value(&mut self.matching, Matching::Path, "Path");
+                ui.selectable_value(&mut self.matching, Matching::Content, "Content")
+                    .on_hover_text("Also match files that were moved or renamed");
+            });
+            ui.end_row();
+        });
+
+        if ui.button("Compare").clicked() {
+            self.compare(drives);
+        }
+
+        match &self.result {
+            None => {}
+            Some(Err(error)) => {
+                ui.label(error);
+            }
+            Some(Ok(diff)) => {
+                ScrollArea::vertical().show(ui, |ui| {
+                    ui.set_width(ui.available_width());
+                    diff_section(
+                        ui,
+                        "Only left",
+                        diff.only_left
+                            .iter()
+                            .map(|(path, info)| (Some((path, info)), None)),
+                    );
+                    diff_section(
+                        ui,
+                        "Only right",
+                        diff.only_right
+                            .iter()
+                            .map(|(path, info)| (None, Some((path, info)))),
+                    );
+                    diff_section(
+                        ui,
+                        "Same name, different content",
+                        diff.changed.iter().map(|(path, left_info, right_info)| {
+                            (Some((path, left_info)), Some((path, right_info)))
+                        }),
+                    );
+                    diff_section(
+                        ui,
+                        "Identical",
+                        diff.identical.iter().map(|(left_path, right_path, info)| {
+                            (Some((left_path, info)), Some((right_path, info)))
+                        }),
+                    );
+                });
+            }
+        }
+    }
+}
+
+#[derive(Default)]
+struct CoverageView {
+    source: Option<String>,
+    /// A folder inside the source drive or empty for all of it.
+    subfolder: String,
+    targets: BTreeSet<String>,
+    job: Option<JoinHandle<Result<Coverage, String>>>,
+    result: Option<Result<Coverage, String>>,
+    export: Export,
+}
+
+impl CoverageView {
+    fn show(&mut self, ui: &mut Ui, drives: &[Drive], drive_path: impl Fn(&str) -> PathBuf) {
+        if let Some(result) = self.job.try_join() {
+            self.result = Some(result.expect("coverage check shouldn't panic"));
+        }
+
+        let catalogs = drives
+            .iter()
+            .filter_map(|drive| Some((&drive.name, drive.catalog()?)))
+            .collect::<Vec<_>>();
+
+        Grid::new("coverage").show(ui, |ui| {
+            ui.label("Source");
+            ui.horizontal(|ui| {
+                ComboBox::from_id_salt("source")
+                    .selected_text(self.source.as_deref().unwrap_or("select a drive"))
+                    .show_ui(ui, |ui| {
+                        for (name, _) in &catalogs {
+                            ui.selectable_value(&mut self.source, Some(name.to_string()), *name);
+                        }
+                    });
+                ui.add(TextEdit::singleline(&mut self.subfolder).hint_text("optional folder"));
+            });
+            ui.end_row();
+
+            ui.label("Backups");
+            ui.horizontal_wrapped(|ui| {
+                for (name, _) in &catalogs {
+                    if self.source.as_ref() == Some(*name) {
+                        continue;
+                    }
+
+                    let mut checked = self.targets.contains(*name);
+                    if ui.checkbox(&mut checked, *name).changed() {
+                        if checked {
+                            self.targets.insert(name.to_string());
+                        } else {
+                            self.targets.remove(*name);
+                        }
+                    }
+                }
+            });
+            ui.end_row();
+        });
+
+        let check = ui
+            .horizontal(|ui| {
+                let check = ui
+                    .add_enabled(
+                        self.source.is_some() && self.job.is_none(),
+                        egui::Button::new("Check"),
+                    )
+                    .clicked();
+                if self.job.is_some() {
+                    ui.spinner();
+                }
+                check
+            })
+            .inner;
+
+        if check && let Some(source) = &self.source {
+            // trees that aren't loaded are read by the job, but not kept, since checking coverage
+            // doesn't enable drives
+            let tree = |name: &String| {
+                let catalog = drives.iter().find(|drive| drive.name == *name)?.catalog()?;
+                Some(catalog.tree().cloned().ok_or_else(|| drive_path(name)))
+            };
+            let source_tree = tree(source);
+            let target_trees = self
+                .targets
+                .iter()
+                .filter(|name| *name != source)
+                .filter_map(tree)
+                .collect::<Vec<_>>();
+            let subfolder = PathBuf::from(&self.subfolder);
+            let source_path = Path::new(source).join(&subfolder);
+            let ctx = ui.ctx().clone();
+            self.job = Some(thread::spawn(move || {
+                let load = |tree: Result<Arc<Tree>, PathBuf>| {
+                    tree.or_else(|path| {
+                        Catalog::read_tree(&path)
+                            .map(Arc::new)
+                            .map_err(|error| format!("failed to load {}: {error}", path.display()))
+                    })
+                };
+                let coverage = source_tree.map(load).transpose().and_then(|source_tree| {
+                    let target_trees = target_trees
+                        .into_iter()
+                        .map(load)
+                        .collect::<Result<Vec<_>, _>>()?;
+                    source_tree
+                        .as_ref()
+                        .and_then(|tree| tree.root().get(&subfolder))
+                        .map(|entry| {
+                            Coverage::new(
+                                source_path.clone(),
+                                entry,
+                                target_trees.iter().map(|tree| tree.root()),
+                            )
+                        })
+                        .ok_or_else(|| format!("{} does not exist", source_path.display()))
+                });
+                ctx.request_repaint();
+                coverage
+            }));
+        }
+
+        match &self.result {
+            None => {}
+            Some(Err(error)) => {
+                ui.label(error);
+            }
+            Some(Ok(coverage)) => {
+                ui.horizontal(|ui| {
+                    let uncovered_bytes = coverage.uncovered_bytes.format_size(SIZE_FORMAT);
+                    let total_bytes = coverage.total_bytes.format_size(SIZE_FORMAT);
+                    ui.label(format!(
+                        "{} files ({uncovered_bytes} of {total_bytes}) are not backed up",
+                        coverage.uncovered.len()
+                    ));
+                    self.export.button(ui, "coverage.csv", || coverage.to_csv());
+                });
+
+                let row_height = ui.text_style_height(&TextStyle::Body);
+                ScrollArea::vertical().show_rows(
+                    ui,
+                    row_height,
+                    coverage.uncovered.len(),
+                    |ui, rows| {
+                        ui.set_width(ui.available_width());
+                        for (path, info) in &coverage.uncovered[rows] {
+                            let bytes = info.bytes.format_size(SIZE_FORMAT);
+                            let path = coverage.source.join(path);
+                            ui.label(format!("{} ({bytes})", path.to_string_lossy()));
+                        }
+                    },
+                );
+            }
+        }
+    }
+}
+
+/// The content of each enabled drive that exists nowhere else.
+#[derive(Default)]
+struct UniqueView {
+    export: Export,
+}
+
+impl UniqueView {
+    fn show(&mut self, ui: &mut Ui, drives: &[UniqueContent]) {
+        ui.horizontal(|ui| {
+            ui.label("Content that only exists on a single enabled drive");
+            self.export
+                .button(ui, "unique.csv", || UniqueContent::to_csv(drives));
+        });
+
+        let row_height = ui.text_style_height(&TextStyle::Body);
+        ScrollArea::vertical().show(ui, |ui| {
+            ui.set_width(ui.available_width());
+            for unique in drives {
+                let bytes = unique.bytes.format_size(SIZE_FORMAT);
+                let files = unique.files.len();
+                CollapsingHeader::new(format!("{}: {bytes} unique in {files} files", unique.drive))
+                    .id_salt(&unique.drive)
+                    .show(ui, |ui| {
+                        ScrollArea::vertical()
+                            .id_salt(&unique.drive)
+                            .max_height(row_height * 20.0)
+                            .show_rows(ui, row_height, files, |ui, rows| {
+                                for (path, info) in &unique.files[rows] {
+                                    let bytes = info.bytes.format_size(SIZE_FORMAT);
+                                    ui.label(format!("{} ({bytes})", path.to_string_lossy()));
+                                }
+                            });
                     });
+            }
+        });
+    }
+}
+
+#[derive(Default)]
+struct RedundancyView {
+    export: Export,
+}
+
+impl RedundancyView {
+    fn show(&mut self, ui: &mut Ui, redundancy: &Redundancy) {
+        let format = |bytes: u64| bytes.format_size(SIZE_FORMAT);
+
+        self.export
+            .button(ui, "redundancy.csv", || redundancy.to_csv());
+
+        ScrollArea::both().show(ui, |ui| {
+            Grid::new("breakdown").striped(true).show(ui, |ui| {
+                ui.strong("Drive");
+                ui.strong("Within drive")
+                    .on_hover_text("Additional copies of files on the same drive");
+                ui.strong("On other drives")
+                    .on_hover_text("Files that also exist on another enabled drive");
+                ui.strong("Unique")
+                    .on_hover_text("Files that exist nowhere else");
+                ui.end_row();
+
+                for drive in &redundancy.drives {
+                    ui.label(&drive.drive);
+                    ui.label(format(drive.within_drive));
+                    ui.label(format(drive.on_other_drives));
+                    ui.label(format(drive.unique));
+                    ui.end_row();
+                }
+            });
+
+            ui.separator();
+            ui.strong("Shared between drives");
+            Grid::new("shared").striped(true).show(ui, |ui| {
+                ui.label("");
+                for drive in &redundancy.drives {
+                    ui.strong(&drive.drive);
+                }
+                ui.end_row();
+
+                for (drive, shared) in redundancy.drives.iter().zip(&redundancy.shared) {
+                    ui.strong(&drive.drive);
+                    for &bytes in shared {
+                        ui.label(format(bytes));
+                    }
+                    ui.end_row();
                 }
             });
         });
-    })
+    }
+}
+
+/// A button that saves a report to a file chosen by the user.
+#[derive(Default)]
+struct Export {
+    job: Option<JoinHandle<io::Result<()>>>,
+    error: Option<String>,
+}
+
+impl Export {
+    fn button(&mut self, ui: &mut Ui, file_name: &str, contents: impl FnOnce() -> String) {
+        if let Some(result) = self.job.try_join() {
+            self.error = result
+                .expect("export shouldn't panic")
+                .err()
+                .map(|error| format!("failed to export: {error}"));
+        }
+
+        if ui
+            .add_enabled(self.job.is_none(), egui::Button::new("Export..."))
+            .clicked()
+        {
+            let contents = contents();
+            let file_name = file_name.to_string();
+            let ctx = ui.ctx().clone();
+            self.job = Some(thread::spawn(move || {
+                let path = rfd::FileDialog::new()
+                    .set_file_name(file_name)
+                    .add_filter("CSV", &["csv"])
+                    .save_file();
+                ctx.request_repaint();
+                path.map_or(Ok(()), |path| fs::write(path, contents))
+            }));
+        }
+
+        if let Some(error) = &self.error {
+            ui.label(error);
+        }
+    }
+}
+
+/// Shows files of the left and right side next to each other.
+fn diff_section<'a>(
+    ui: &mut Ui,
+    title: &str,
+    rows: impl ExactSizeIterator<
+        Item = (
+            Option<(&'a PathBuf, &'a EntryInfo)>,
+            Option<(&'a PathBuf, &'a EntryInfo)>,
+        ),
+    >,
+) {
+    CollapsingHeader::new(format!("{title} ({} files)", rows.len())).show(ui, |ui| {
+        Grid::new(title).striped(true).show(ui, |ui| {
+            for (left, right) in rows {
+                for side in [left, right] {
+                    if let Some((path, info)) = side {
+                        let bytes = info.bytes.format_size(SIZE_FORMAT);
+                        ui.label(format!("{} ({bytes})", path.to_string_lossy()));
+                    } else {
+                        ui.label("");
+                    }
+                }
+                ui.end_row();
+            }
+        });
+    });
+}
+
+fn scan_progress(ui: &mut Ui, state: &ScanState) {
+    let response = ui
+        .horizontal(|ui| {
+            if let Some(files) = state.counting() {
+                ui.label(format!("counting... {files} files"));
+                return;
+            }
+
+            let progress = state.progress();
+            if let Some(fraction) = progress.fraction {
+                ui.add(
+                    ProgressBar::new(fraction)
+                        .desired_width(150.0)
+                        .show_percentage(),
+                );
+            }
+
+            let bytes_per_second = (progress.bytes_per_second as u64).format_size(SIZE_FORMAT);
+            let files_per_second = progress.files_per_second.round();
+            ui.label(format!("{bytes_per_second}/s, {files_per_second} files/s"));
+
+            if let Some(remaining) = progress.remaining {
+                ui.label(format!("{} left", format_duration(remaining)));
+            }
+        })
+        .response;
+
+    if let Some(path) = state.current_path() {
+        response.on_hover_text(path.display().to_string());
+    }
+}
+
+/// Formats a duration roughly, e.g. as `1h 05m` or `42s`.
+fn format_duration(duration: Duration) -> String {
+    let seconds = duration.as_secs();
+    let (hours, minutes, seconds) = (seconds / 3600, seconds / 60 % 60, seconds % 60);
+    if hours > 0 {
+        format!("{hours}h {minutes:02}m")
+    } else if minutes > 0 {
+        format!("{minutes}m {seconds:02}s")
+    } else {
+        format!("{seconds}s")
+    }
+}
+
+fn throttle_settings(ui: &mut Ui, state: &ScanState) {
+    const MB: u64 = 1_000_000;
+
+    Grid::new("throttle").show(ui, |ui| {
+        ui.label("Bandwidth limit");
+        let mut megabytes = state.bandwidth_limit().map_or(0, |limit| limit.get() / MB);
+        if ui
+            .add(
+                DragValue::new(&mut megabytes)
+                    .range(0..=u64::MAX / MB)
+                    .suffix(" MB/s"),
+            )
+            .on_hover_text("0 for unlimited")
+            .changed()
+        {
+            state.set_bandwidth_limit(NonZeroU64::new(megabytes * MB));
+        }
+        ui.end_row();
+
+        ui.label("Thread limit");
+        let mut threads = state.thread_limit().map_or(0, NonZeroUsize::get);
+        if ui
+            .add(
+                DragValue::new(&mut threads)
+                    .range(0..=available_parallelism().map_or(1, NonZeroUsize::get)),
+            )
+            .on_hover_text("0 for unlimited")
+            .changed()
+        {
+            state.set_thread_limit(NonZeroUsize::new(threads));
+        }
+        ui.end_row();
+
+        if cfg!(target_os = "linux") {
+            let mut idle_io_priority = state.idle_io_priority();
+            if ui
+                .checkbox(&mut idle_io_priority, "Idle I/O priority")
+                .on_hover_text("Only read while no other process is using the disk")
+                .changed()
+            {
+                state.set_idle_io_priority(idle_io_priority);
+            }
+            ui.end_row();
+        }
+    });
 }
 
 fn dirs_files_bytes(ui: &mut Ui, bytes: u64, dirs: u64, files: u64) {
@@ -296,61 +1694,351 @@ impl Drive {
             state,
         }
     }
+
+    /// Returns the catalog, if it is loaded.
+    fn catalog(&self) -> Option<&Catalog> {
+        if let DriveState::Done {
+            catalog: Some((catalog, _)),
+            ..
+        } = &self.state
+        {
+            Some(catalog)
+        } else {
+            None
+        }
+    }
+
+    /// Saves the result of a scan, which has to be in [`DriveState::Scanning`].
+    ///
+    /// New scans get a unique name first, while resumed scans replace their partial catalog.
+    fn finish_scan(
+        &mut self,
+        new_entry: thread::Result<Option<Entry>>,
+        drive_path: impl Fn(&str) -> PathBuf,
+    ) {
+        let DriveState::Scanning {
+            root,
+            resumed,
+            state,
+            compression,
+            ..
+        } = &self.state
+        else {
+            panic!("drive should be scanning");
+        };
+
+        if !resumed {
+            let name = self.name.clone();
+            let mut index = 1;
+            while drive_path(&self.name)
+                .try_exists()
+                .ok()
+                .is_none_or(identity)
+            {
+                self.name = format!("{name} ({index})");
+                index += 1;
+            }
+        }
+
+        let root = Some(root.clone());
+        let options = state.options();
+        let compression = *compression;
+        let error_log = state.clone_error_log();
+        self.state = DriveState::save(
+            &drive_path(&self.name),
+            new_entry
+                .unwrap_or_default()
+                .map(|entry| Catalog::new(root, options, compression, Tree::new(entry))),
+            error_log,
+        );
+    }
 }
 
 enum DriveState {
     Scanning {
+        root: PathBuf,
+        /// Resumed scans replace their existing partial catalog.
+        resumed: bool,
         state: Arc<ScanState>,
+        /// The compression of the resulting catalog.
+        compression: Compression,
         join_handle: Option<JoinHandle<Option<Entry>>>,
     },
     Done {
-        entry: Option<(Entry, bool)>,
+        catalog: Option<(Catalog, bool)>,
+        watch: Option<Watch>,
+        /// Loads the tree of the catalog once it is needed or rehashes or saves the catalog.
+        job: Option<CatalogJob>,
+        /// What to do with the catalog once it is loaded, in the order it was asked for.
+        pending: VecDeque<TreeAction>,
         error_log: Vec<String>,
     },
+    /// The catalog couldn't be opened, so it can only be deleted, renamed or recovered.
+    Broken {
+        error: String,
+        /// Why recovering from the backup failed, if it was attempted.
+        backup_error: Option<String>,
+    },
 }
 
 impl DriveState {
-    fn save(path: &Path, entry: Option<Entry>, mut error_log: Vec<String>) -> Self {
-        if let Some(entry) = &entry {
-            match postcard::to_allocvec(entry) {
-                Ok(data) => {
-                    if let Err(error) = fs::write(path, &data) {
-                        error_log.push(error.to_string())
-                    }
-                }
-                Err(error) => {
-                    error_log.push(error.to_string());
-                }
-            };
+    fn save(path: &Path, catalog: Option<Catalog>, mut error_log: Vec<String>) -> Self {
+        if let Some(catalog) = &catalog {
+            write_catalog(path, catalog, &mut error_log);
         }
 
         Self::Done {
-            entry: entry.map(|entry| (entry, false)),
+            catalog: catalog.map(|catalog| (catalog, false)),
+            watch: None,
+            job: None,
+            pending: VecDeque::new(),
             error_log,
         }
     }
 
     fn load(path: &Path) -> Self {
-        Self::Done {
-            entry: Some((
-                postcard::from_bytes(&fs::read(path).unwrap()).unwrap(),
-                false,
-            )),
-            error_log: Default::default(),
+        match Catalog::open(path) {
+            Ok(catalog) => Self::Done {
+                catalog: Some((catalog, false)),
+                watch: None,
+                job: None,
+                pending: VecDeque::new(),
+                error_log: Default::default(),
+            },
+            Err(error) => Self::Broken {
+                error: error.to_string(),
+                backup_error: None,
+            },
         }
     }
 
-    fn scan(path: PathBuf) -> DriveState {
-        let state = ScanState::new();
+    fn scan(
+        root: PathBuf,
+        options: ScanOptions,
+        compression: Compression,
+        scheduler: &Scheduler,
+    ) -> DriveState {
+        Self::scan_with_previous(root, None, options, compression, scheduler)
+    }
+
+    /// Continues a partial scan with the options it was started with, reusing everything that
+    /// was already scanned.
+    ///
+    /// The catalog's root must be known and its tree loaded.
+    fn resume(catalog: Catalog, count: bool, scheduler: &Scheduler) -> DriveState {
+        let previous = catalog
+            .tree()
+            .expect("resumed catalog should be loaded")
+            .root()
+            .to_entry();
+        let root = catalog.root.expect("resumed scan should have a root");
+        let options = ScanOptions {
+            count,
+            ..catalog.options
+        };
+        Self::scan_with_previous(
+            root,
+            Some(previous),
+            options,
+            catalog.compression,
+            scheduler,
+        )
+    }
+
+    fn scan_with_previous(
+        root: PathBuf,
+        previous: Option<Entry>,
+        options: ScanOptions,
+        compression: Compression,
+        scheduler: &Scheduler,
+    ) -> DriveState {
+        let resumed = previous.is_some();
+
+        // if the device can't be determined, the scan itself fails and logs why
+        let device = scheduler.device(&root).ok();
+        let sequential = device.as_ref().is_some_and(|device| device.rotational);
+        let state = ScanState::new(options, sequential);
+
         let join_handle = Some(thread::spawn({
+            let root = root.clone();
             let state = state.clone();
-            move || Entry::scan(&path, &state)
+            move || {
+                let scan = || {
+                    if state.options().count {
+                        state.count(&root);
+                    }
+                    Entry::resume(&root, previous, &state)
+                };
+
+                match device {
+                    Some(device) => device.pool.install(scan),
+                    None => scan(),
+                }
+            }
         }));
 
-        Self::Scanning { state, join_handle }
+        Self::Scanning {
+            root,
+            resumed,
+            state,
+            compression,
+            join_handle,
+        }
+    }
+
+    fn is_scanning(&self) -> bool {
+        matches!(self, Self::Scanning { .. })
+    }
+
+    /// Whether the catalog may still be written in the background, so that it cannot be renamed
+    /// or deleted.
+    fn is_saving(&self) -> bool {
+        matches!(self, Self::Done { watch, job, .. } if watch.is_some() || job.is_some())
+    }
+}
+
+/// Loads, rehashes or saves the catalog of a drive in the background, since that takes a while
+/// for large ones.
+struct CatalogJob {
+    join_handle: Option<JoinHandle<JobResult>>,
+    /// What the job does, to show while it runs.
+    description: &'static str,
+}
+
+enum JobResult {
+    /// The tree of the catalog or why it couldn't be loaded.
+    Loaded(Result<Tree, CatalogError>),
+    /// The new version of the catalog, along with why saving it failed.
+    Saved(Catalog, Option<CatalogError>),
+}
+
+/// Something that requires the tree of a catalog.
+#[derive(Clone, Copy)]
+enum TreeAction {
+    /// Includes the drive in the analysis, which was already checked.
+    Enable,
+    DirHashing(DirHashing),
+    Compression(Compression),
+    Resume,
+    Watch,
+}
+
+impl CatalogJob {
+    fn load(path: PathBuf, ctx: egui::Context) -> Self {
+        Self::start("Loading catalog", ctx, move || {
+            JobResult::Loaded(Catalog::read_tree(&path))
+        })
+    }
+
+    /// Rehashes the directories of `catalog`, whose tree has to be loaded, and saves it.
+    fn rehash(
+        path: PathBuf,
+        mut catalog: Catalog,
+        dir_hashing: DirHashing,
+        ctx: egui::Context,
+    ) -> Self {
+        Self::start("Rehashing folders", ctx, move || {
+            let mut tree = Tree::clone(catalog.tree().expect("rehashed tree should be loaded"));
+            tree.rehash_dirs(dir_hashing);
+            catalog.options.dir_hashing = dir_hashing;
+            catalog.set_tree(tree);
+            let error = catalog.save(&path).err();
+            JobResult::Saved(catalog, error)
+        })
     }
 
-    fn is_done(&self) -> bool {
-        matches!(self, Self::Done { .. })
+    fn save(path: PathBuf, catalog: Catalog, ctx: egui::Context) -> Self {
+        Self::start("Saving catalog", ctx, move || {
+            let error = catalog.save(&path).err();
+            JobResult::Saved(catalog, error)
+        })
+    }
+
+    fn start(
+        description: &'static str,
+        ctx: egui::Context,
+        job: impl FnOnce() -> JobResult + Send + 'static,
+    ) -> Self {
+        let join_handle = thread::spawn(move || {
+            let result = job();
+            ctx.request_repaint();
+            result
+        });
+
+        Self {
+            join_handle: Some(join_handle),
+            description,
+        }
+    }
+}
+
+/// Watches the root of a `catalog` for changes, logging why that failed otherwise.
+///
+/// Updates are applied to a copy of the catalog, whose tree has to be loaded, and saved to `path`.
+fn start_watch(
+    path: &Path,
+    catalog: &Catalog,
+    ctx: &egui::Context,
+    error_log: &mut Vec<String>,
+) -> Option<Watch> {
+    let root = catalog.root.as_ref()?;
+    Watch::new(path.to_path_buf(), catalog.clone(), ctx.clone())
+        .inspect_err(|error| error_log.push(format!("failed to watch {}: {error}", root.display())))
+        .ok()
+}
+
+fn write_catalog(path: &Path, catalog: &Catalog, error_log: &mut Vec<String>) {
+    if let Err(error) = catalog.save(path) {
+        error_log.push(error.to_string());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::scan::tests::{dir, file};
+
+    #[test]
+    fn truncated_catalog_is_broken_and_recovered_from_backup() {
+        let temp_dir = std::env::temp_dir().join(format!("ssdedupe-main-{}", std::process::id()));
+        fs::create_dir_all(&temp_dir).unwrap();
+        let path = temp_dir.join(format!("drive{DRIVE_EXTENSION}"));
+        let catalog = |entry| {
+            Catalog::new(
+                None,
+                ScanOptions::default(),
+                Compression::Zstd,
+                Tree::new(entry),
+            )
+        };
+        catalog(dir([("a", file(1)), ("b", file(2))]))
+            .save(&path)
+            .unwrap();
+        catalog(dir([("a", file(1))])).save(&path).unwrap();
+
+        let len = fs::metadata(&path).unwrap().len();
+        fs::File::options()
+            .write(true)
+            .open(&path)
+            .unwrap()
+            .set_len(len / 2)
+            .unwrap();
+        assert!(matches!(DriveState::load(&path), DriveState::Broken { .. }));
+
+        // like the recover button
+        let backup = Catalog::open_backup(&path).unwrap();
+        assert!(matches!(
+            DriveState::save(&path, Some(backup), Vec::new()),
+            DriveState::Done { ref error_log, .. } if error_log.is_empty()
+        ));
+        let DriveState::Done {
+            catalog: Some((catalog, _)),
+            ..
+        } = DriveState::load(&path)
+        else {
+            panic!("recovered catalog should open");
+        };
+        assert_eq!(catalog.summary().files, 2);
+        fs::remove_dir_all(&temp_dir).unwrap();
     }
 }
@@ -0,0 +1,367 @@
+use std::{
+    collections::{HashMap, HashSet},
+    path::PathBuf,
+};
+
+use rayon::iter::{IntoParallelIterator, ParallelIterator};
+
+use crate::{
+    scan::{EntryInfo, EntryKind},
+    tree::{EntryRef, Roots},
+};
+
+/// Files that occur more often than this are ignored when looking for near-duplicates.
+///
+/// Such files (e.g. the same license file in hundreds of projects) say little about which
+/// directories belong together, but would pair up every directory that contains them. They are
+/// left out of the sizes of directories as well, so that they don't prevent subsets.
+const MAX_OCCURRENCES: usize = 64;
+
+/// Two directories that share most of their files without being exact duplicates.
+///
+/// Sizes only count distinct files, so a file that exists twice within a directory counts once,
+/// and leave out files that occur too often to matter.
+#[derive(Clone, Debug)]
+pub struct NearDuplicate {
+    /// The directory with fewer bytes.
+    pub smaller: PathBuf,
+    pub larger: PathBuf,
+    pub smaller_bytes: u64,
+    pub larger_bytes: u64,
+    /// The bytes of all files that exist in both directories.
+    pub shared_bytes: u64,
+}
+
+impl NearDuplicate {
+    /// Whether all files of the smaller directory also exist somewhere in the larger one.
+    pub fn is_subset(&self) -> bool {
+        self.shared_bytes == self.smaller_bytes
+    }
+
+    /// The share of the larger directory that also exists in the smaller one.
+    pub fn overlap(&self) -> f64 {
+        self.shared_bytes as f64 / self.larger_bytes as f64
+    }
+}
+
+/// Finds all pairs of directories where one is a subset of the other or that share at least
+/// `min_overlap` of their bytes, sorted by their shared bytes.
+///
+/// Exact duplicates are not included, since those are already found by
+/// [`Entry::unfiltered_duplicates`]. Similar to [`Roots::filter_duplicates_by_prefix`], pairs are
+/// omitted if they are already implied by a pair of their parents. Subsets are only reported for
+/// the deepest directory that still contains all of the subset.
+pub fn near_duplicates(roots: &Roots, min_overlap: f64) -> Vec<NearDuplicate> {
+    let mut dirs = Vec::new();
+    let mut occurrences = HashMap::<EntryInfo, Vec<usize>>::new();
+    for (name, root) in roots.iter() {
+        collect_dirs(
+            root,
+            PathBuf::from(name.as_str()),
+            None,
+            &mut dirs,
+            &mut occurrences,
+        );
+    }
+
+    for (info, parents) in &occurrences {
+        if parents.len() > MAX_OCCURRENCES {
+            let containing = parents
+                .iter()
+                .flat_map(|&parent| ancestors(&dirs, parent))
+                .collect::<HashSet<_>>();
+            for index in containing {
+                dirs[index].bytes -= info.bytes;
+            }
+        }
+    }
+
+    let shared_bytes = occurrences
+        .into_par_iter()
+        .filter(|(_, parents)| (2..=MAX_OCCURRENCES).contains(&parents.len()))
+        .fold(
+            HashMap::<(usize, usize), u64>::new,
+            |mut shared_bytes, (info, parents)| {
+                let containing = parents
+                    .into_iter()
+                    .flat_map(|parent| ancestors(&dirs, parent))
+                    .collect::<HashSet<_>>();
+                for &first in &containing {
+                    for &second in &containing {
+                        if first < second && !is_ancestor(&dirs, first, second) {
+                            *shared_bytes.entry((first, second)).or_default() += info.bytes;
+                        }
+                    }
+                }
+                shared_bytes
+            },
+        )
+        .reduce(HashMap::new, |mut shared_bytes, other| {
+            for (pair, bytes) in other {
+                *shared_bytes.entry(pair).or_default() += bytes;
+            }
+            shared_bytes
+        });
+
+    let pairs = shared_bytes
+        .into_iter()
+        .filter_map(|((first, second), shared_bytes)| {
+            let (first_dir, second_dir) = (&dirs[first], &dirs[second]);
+            // exact duplicates are kept until the end, since they imply other pairs as well
+            if first_dir.partial || second_dir.partial {
+                return None;
+            }
+
+            let (smaller, larger) = if first_dir.bytes <= second_dir.bytes {
+                (first, second)
+            } else {
+                (second, first)
+            };
+            let pair = Pair {
+                smaller,
+                larger,
+                shared_bytes,
+            };
+            (pair.is_subset(&dirs) || pair.overlap(&dirs) >= min_overlap)
+                .then_some(((first, second), pair))
+        })
+        .collect::<HashMap<_, _>>();
+
+    let get = |first: usize, second: usize| pairs.get(&(first.min(second), first.max(second)));
+    // either way for exact duplicates
+    let is_subset_of = |smaller: usize, larger: usize| {
+        get(smaller, larger).is_some_and(|pair| pair.shared_bytes == dirs[smaller].bytes)
+    };
+
+    let mut near_duplicates = pairs
+        .values()
+        .filter(|pair| {
+            let is_subset = pair.is_subset(&dirs);
+            let (smaller, larger) = (&dirs[pair.smaller], &dirs[pair.larger]);
+            if smaller.info == larger.info {
+                return false;
+            }
+
+            if let (Some(smaller_parent), Some(larger_parent)) = (smaller.parent, larger.parent) {
+                let implied = if is_subset {
+                    is_subset_of(smaller_parent, larger_parent)
+                } else {
+                    get(smaller_parent, larger_parent)
+                        .is_some_and(|parents| !parents.is_subset(&dirs))
+                };
+                if implied {
+                    return false;
+                }
+            }
+
+            if is_subset {
+                // the parent is a subset as well, which includes this directory
+                if smaller
+                    .parent
+                    .is_some_and(|smaller_parent| is_subset_of(smaller_parent, pair.larger))
+                {
+                    return false;
+                }
+
+                // a subdirectory already contains everything
+                if larger
+                    .children
+                    .iter()
+                    .any(|&child| is_subset_of(pair.smaller, child))
+                {
+                    return false;
+                }
+            }
+
+            true
+        })
+        .map(|pair| NearDuplicate {
+            smaller: dirs[pair.smaller].path.clone(),
+            larger: dirs[pair.larger].path.clone(),
+            smaller_bytes: dirs[pair.smaller].bytes,
+            larger_bytes: dirs[pair.larger].bytes,
+            shared_bytes: pair.shared_bytes,
+        })
+        .collect::<Vec<_>>();
+
+    near_duplicates.sort_unstable_by(|a, b| {
+        (b.shared_bytes, &a.smaller, &a.larger).cmp(&(a.shared_bytes, &b.smaller, &b.larger))
+    });
+    near_duplicates
+}
+
+/// A directory in pre-order, so that all its descendants immediately follow it.
+struct DirContent {
+    path: PathBuf,
+    info: EntryInfo,
+    partial: bool,
+    parent: Option<usize>,
+    children: Vec<usize>,
+    /// The index after the last descendant.
+    end: usize,
+    /// The bytes of all distinct files.
+    bytes: u64,
+}
+
+struct Pair {
+    smaller: usize,
+    larger: usize,
+    shared_bytes: u64,
+}
+
+impl Pair {
+    fn is_subset(&self, dirs: &[DirContent]) -> bool {
+        self.shared_bytes == dirs[self.smaller].bytes
+    }
+
+    fn overlap(&self, dirs: &[DirContent]) -> f64 {
+        self.shared_bytes as f64 / dirs[self.larger].bytes as f64
+    }
+}
+
+/// Collects all directories and for each non-empty file the directories it occurs in.
+///
+/// Returns the distinct files of `entry`.
+fn collect_dirs(
+    entry: EntryRef,
+    path: PathBuf,
+    parent: Option<usize>,
+    dirs: &mut Vec<DirContent>,
+    occurrences: &mut HashMap<EntryInfo, Vec<usize>>,
+) -> HashSet<EntryInfo> {
+    let info = entry.info();
+    if info.kind != EntryKind::Dir {
+        return HashSet::new();
+    }
+
+    let index = dirs.len();
+    if let Some(parent) = parent {
+        dirs[parent].children.push(index);
+    }
+    dirs.push(DirContent {
+        path: path.clone(),
+        info,
+        partial: entry.is_partial(),
+        parent,
+        children: Vec::new(),
+        end: 0,
+        bytes: 0,
+    });
+
+    let mut files = HashSet::new();
+    for (file_name, entry) in entry.children() {
+        let info = entry.info();
+        match info.kind {
+            EntryKind::Dir => {
+                let child_files =
+                    collect_dirs(entry, path.join(file_name), Some(index), dirs, occurrences);
+                files.extend(child_files);
+            }
+            EntryKind::File if info.bytes > 0 => {
+                occurrences.entry(info).or_default().push(index);
+                files.insert(info);
+            }
+            EntryKind::File => {}
+        }
+    }
+
+    dirs[index].end = dirs.len();
+    dirs[index].bytes = files.iter().map(|info| info.bytes).sum();
+    files
+}
+
+fn ancestors(dirs: &[DirContent], index: usize) -> impl Iterator<Item = usize> + '_ {
+    std::iter::successors(Some(index), |&index| dirs[index].parent)
+}
+
+/// Whether `first` is an ancestor of `second`, given that `first` comes first.
+fn is_ancestor(dirs: &[DirContent], first: usize, second: usize) -> bool {
+    second < dirs[first].end
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use super::*;
+    use crate::scan::tests::{dir, file, roots};
+
+    fn pairs(roots: &Roots, min_overlap: f64) -> Vec<(String, String, bool)> {
+        near_duplicates(roots, min_overlap)
+            .into_iter()
+            .map(|near_duplicate| {
+                (
+                    near_duplicate.smaller.to_string_lossy().into_owned(),
+                    near_duplicate.larger.to_string_lossy().into_owned(),
+                    near_duplicate.is_subset(),
+                )
+            })
+            .collect()
+    }
+
+    #[test]
+    fn subset_is_reported_for_deepest_dir() {
+        let roots = roots([
+            (
+                "a",
+                dir([("x", dir([("1", file(1)), ("2", file(2)), ("3", file(3))]))]),
+            ),
+            ("b", dir([("y", dir([("1", file(1)), ("2", file(2))]))])),
+        ]);
+        assert_eq!(pairs(&roots, 1.0), [("b".into(), "a/x".into(), true)]);
+    }
+
+    #[test]
+    fn overlap_has_to_reach_minimum() {
+        let files = |other| {
+            (100..=108)
+                .chain([other])
+                .map(|bytes| (bytes.to_string(), file(bytes)))
+        };
+        let roots = roots([("a", dir([("x", dir(files(1))), ("y", dir(files(2)))]))]);
+        assert_eq!(pairs(&roots, 0.99), [("a/x".into(), "a/y".into(), false)]);
+        assert_eq!(pairs(&roots, 1.0), []);
+    }
+
+    #[test]
+    fn exact_duplicates_are_ignored() {
+        let roots = roots([
+            ("a", dir([("1", file(1)), ("2", file(2))])),
+            ("b", dir([("1", file(1)), ("2", file(2))])),
+        ]);
+        assert_eq!(pairs(&roots, 0.5), []);
+    }
+
+    #[test]
+    fn exact_duplicates_imply_subsets_of_their_parents() {
+        let docs = || ("docs", dir([("1", file(1)), ("2", file(2))]));
+        let roots = roots([
+            ("a", dir([docs(), ("extra", file(30))])),
+            ("b", dir([docs(), ("other", file(90))])),
+        ]);
+        assert_eq!(pairs(&roots, 0.5), []);
+    }
+
+    #[test]
+    fn frequent_files_are_ignored_on_both_sides() {
+        let license = || ("license", file(1000));
+        let projects = (0..MAX_OCCURRENCES).map(|index| {
+            let bytes = 2000 + index as u64;
+            (index.to_string(), dir([license(), ("main", file(bytes))]))
+        });
+        let roots = roots([
+            ("a", dir(projects)),
+            ("x", dir([license(), ("1", file(1))])),
+            ("y", dir([license(), ("1", file(1)), ("2", file(2))])),
+        ]);
+
+        let near_duplicates = near_duplicates(&roots, 1.0);
+        assert_eq!(near_duplicates.len(), 1);
+        let near_duplicate = &near_duplicates[0];
+        assert_eq!(near_duplicate.smaller, Path::new("x"));
+        assert_eq!(near_duplicate.larger, Path::new("y"));
+        assert_eq!(near_duplicate.smaller_bytes, 1);
+        assert_eq!(near_duplicate.larger_bytes, 3);
+        assert!(near_duplicate.is_subset());
+    }
+}
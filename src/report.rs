@@ -0,0 +1,341 @@
+use std::{
+    borrow::Cow,
+    collections::{BTreeMap, HashSet},
+    fmt::Write,
+    path::PathBuf,
+};
+
+use itertools::Itertools;
+
+use crate::{
+    scan::{EntryInfo, EntryKind},
+    tree::{EntryRef, NodeId, Roots},
+};
+
+/// The files of a source that are not backed up on any of the targets.
+#[derive(Clone, Debug)]
+pub struct Coverage {
+    /// The source, as given by the caller.
+    pub source: PathBuf,
+    /// Files whose content doesn't exist on any target, relative to the source.
+    pub uncovered: Vec<(PathBuf, EntryInfo)>,
+    pub uncovered_bytes: u64,
+    pub total_bytes: u64,
+}
+
+impl Coverage {
+    /// Checks which files of `source` are not backed up on any of the `targets`.
+    ///
+    /// Files only have to exist somewhere on a target, regardless of their path. Empty files are
+    /// always considered backed up, since there is nothing to lose.
+    pub fn new<'a>(
+        source_path: PathBuf,
+        source: EntryRef,
+        targets: impl IntoIterator<Item = EntryRef<'a>>,
+    ) -> Self {
+        let backed_up = targets
+            .into_iter()
+            .flat_map(|target| target.file_paths().map(|(info, _)| info))
+            .collect::<HashSet<_>>();
+
+        let uncovered = source
+            .file_paths()
+            .filter(|(info, _)| info.bytes > 0 && !backed_up.contains(info))
+            .map(|(info, path)| (path, info))
+            .collect::<Vec<_>>();
+
+        Self {
+            source: source_path,
+            uncovered_bytes: uncovered.iter().map(|(_, info)| info.bytes).sum(),
+            total_bytes: source.info().bytes,
+            uncovered,
+        }
+    }
+
+    /// Lists all uncovered files with their full path and size.
+    pub fn to_csv(&self) -> String {
+        let mut csv = String::from("path,bytes\n");
+        for (path, info) in &self.uncovered {
+            let path = self.source.join(path);
+            writeln!(csv, "{},{}", csv_field(&path.to_string_lossy()), info.bytes).unwrap();
+        }
+        csv
+    }
+}
+
+/// Content that exists on a single drive and nowhere else.
+#[derive(Clone, Debug)]
+pub struct UniqueContent {
+    pub drive: String,
+    /// Files by their path starting with the drive, including copies on the same drive.
+    pub files: Vec<(PathBuf, EntryInfo)>,
+    /// The bytes of distinct content, so copies on the same drive count once.
+    pub bytes: u64,
+}
+
+impl UniqueContent {
+    /// Finds the unique content of each of the `drives`, given its [`Roots::grouped`] entries.
+    ///
+    /// Every drive is included, even if nothing on it is unique. Empty files are ignored.
+    pub fn per_drive(drives: &Roots, grouped: &BTreeMap<EntryInfo, Vec<NodeId>>) -> Vec<Self> {
+        let mut unique = drives
+            .iter()
+            .map(|(drive, _)| Self {
+                drive: drive.to_string(),
+                files: Vec::new(),
+                bytes: 0,
+            })
+            .collect::<Vec<_>>();
+
+        for (info, ids) in grouped {
+            if info.kind != EntryKind::File || info.bytes == 0 {
+                continue;
+            }
+
+            let Ok(drive) = ids.iter().map(|id| id.root()).all_equal_value() else {
+                continue;
+            };
+
+            let unique = &mut unique[drive];
+            unique.bytes += info.bytes;
+            unique
+                .files
+                .extend(ids.iter().map(|&id| (drives.path(id), *info)));
+        }
+
+        unique
+    }
+
+    /// Lists the unique files of all drives with their full path and size.
+    pub fn to_csv(unique: &[Self]) -> String {
+        let mut csv = String::from("drive,path,bytes\n");
+        for unique in unique {
+            for (path, info) in &unique.files {
+                writeln!(
+                    csv,
+                    "{},{},{}",
+                    csv_field(&unique.drive),
+                    csv_field(&path.to_string_lossy()),
+                    info.bytes
+                )
+                .unwrap();
+            }
+        }
+        csv
+    }
+}
+
+/// How much of each drive is redundant and how much it shares with every other drive.
+///
+/// Only files are taken into account, since directories consist of files.
+#[derive(Clone, Debug, Default)]
+pub struct Redundancy {
+    pub drives: Vec<DriveRedundancy>,
+    /// The bytes of distinct content that exists on both drives, indexed like `drives`.
+    pub shared: Vec<Vec<u64>>,
+}
+
+/// Splits the bytes of all files on a drive, so that they sum up to its total size.
+#[derive(Clone, Debug, Default)]
+pub struct DriveRedundancy {
+    pub drive: String,
+    /// Bytes of additional copies of files that already exist on the same drive.
+    pub within_drive: u64,
+    /// Bytes of files that also exist on other drives.
+    pub on_other_drives: u64,
+    /// Bytes of files that don't exist anywhere else.
+    pub unique: u64,
+}
+
+impl Redundancy {
+    /// Breaks down the redundancy of each of the `drives`, given its [`Roots::grouped`] entries.
+    pub fn new(drives: &Roots, grouped: &BTreeMap<EntryInfo, Vec<NodeId>>) -> Self {
+        let mut drives = drives
+            .iter()
+            .map(|(drive, _)| DriveRedundancy {
+                drive: drive.to_string(),
+                ..Default::default()
+            })
+            .collect::<Vec<_>>();
+        let mut shared = vec![vec![0; drives.len()]; drives.len()];
+
+        for (info, ids) in grouped {
+            if info.kind != EntryKind::File {
+                continue;
+            }
+
+            let copies = ids.iter().map(|id| id.root()).counts();
+
+            for (&index, &count) in &copies {
+                let drive = &mut drives[index];
+                drive.within_drive += info.bytes * (count as u64 - 1);
+                if copies.len() > 1 {
+                    drive.on_other_drives += info.bytes;
+                } else {
+                    drive.unique += info.bytes;
+                }
+
+                for &other in copies.keys() {
+                    shared[index][other] += info.bytes;
+                }
+            }
+        }
+
+        Self { drives, shared }
+    }
+
+    /// Lists the breakdown of each drive, followed by the matrix of shared bytes.
+    pub fn to_csv(&self) -> String {
+        let mut csv = String::from("drive,within drive,on other drives,unique\n");
+        for drive in &self.drives {
+            writeln!(
+                csv,
+                "{},{},{},{}",
+                csv_field(&drive.drive),
+                drive.within_drive,
+                drive.on_other_drives,
+                drive.unique
+            )
+            .unwrap();
+        }
+
+        csv.push_str("\nshared");
+        for drive in &self.drives {
+            write!(csv, ",{}", csv_field(&drive.drive)).unwrap();
+        }
+        csv.push('\n');
+        for (drive, shared) in self.drives.iter().zip(&self.shared) {
+            write!(csv, "{}", csv_field(&drive.drive)).unwrap();
+            for bytes in shared {
+                write!(csv, ",{bytes}").unwrap();
+            }
+            csv.push('\n');
+        }
+
+        csv
+    }
+}
+
+/// Quotes a CSV field if necessary.
+fn csv_field(field: &str) -> Cow<'_, str> {
+    if field.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", field.replace('"', "\"\"")).into()
+    } else {
+        field.into()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::AtomicBool;
+
+    use super::*;
+    use crate::{
+        scan::tests::{dir, file, roots},
+        tree::Tree,
+    };
+
+    fn drives() -> Roots {
+        roots([
+            (
+                "a",
+                dir([
+                    ("1", file(1)),
+                    ("2", file(2)),
+                    ("copy", file(2)),
+                    ("empty", file(0)),
+                ]),
+            ),
+            (
+                "b",
+                dir([("1", file(1)), ("3", file(3)), ("empty", file(0))]),
+            ),
+            ("c", dir::<&str>([])),
+        ])
+    }
+
+    #[test]
+    fn coverage_finds_files_that_are_nowhere_on_the_targets() {
+        let source = Tree::new(dir([
+            ("a", file(1)),
+            ("b", file(2)),
+            ("c,d", file(3)),
+            ("empty", file(0)),
+        ]));
+        let targets = [
+            Tree::new(dir([("x", file(1))])),
+            Tree::new(dir([("sub", dir([("moved", file(2))]))])),
+        ];
+        let coverage = Coverage::new(
+            PathBuf::from("drive/docs"),
+            source.root(),
+            targets.iter().map(Tree::root),
+        );
+
+        let uncovered = coverage
+            .uncovered
+            .iter()
+            .map(|(path, info)| (path.to_str().unwrap(), info.bytes))
+            .collect::<Vec<_>>();
+        assert_eq!(uncovered, [("c,d", 3)]);
+        assert_eq!(coverage.uncovered_bytes, 3);
+        assert_eq!(coverage.total_bytes, 6);
+        assert_eq!(coverage.to_csv(), "path,bytes\n\"drive/docs/c,d\",3\n");
+    }
+
+    #[test]
+    fn unique_content_is_listed_per_drive() {
+        let drives = drives();
+        let grouped = drives.grouped(&AtomicBool::new(false)).unwrap();
+        let unique = UniqueContent::per_drive(&drives, &grouped)
+            .into_iter()
+            .map(|unique| {
+                let mut files = unique
+                    .files
+                    .iter()
+                    .map(|(path, _)| path.to_string_lossy().into_owned())
+                    .collect::<Vec<_>>();
+                files.sort_unstable();
+                (unique.drive, files, unique.bytes)
+            })
+            .collect::<Vec<_>>();
+
+        // copies on the same drive are listed, but only count once
+        assert_eq!(
+            unique,
+            [
+                ("a".into(), vec!["a/2".into(), "a/copy".into()], 2),
+                ("b".into(), vec!["b/3".into()], 3),
+                ("c".into(), vec![], 0),
+            ]
+        );
+    }
+
+    #[test]
+    fn redundancy_adds_up_to_the_size_of_each_drive() {
+        let drives = drives();
+        let grouped = drives.grouped(&AtomicBool::new(false)).unwrap();
+        let redundancy = Redundancy::new(&drives, &grouped);
+
+        let breakdown = redundancy
+            .drives
+            .iter()
+            .map(|drive| {
+                (
+                    drive.drive.as_str(),
+                    drive.within_drive,
+                    drive.on_other_drives,
+                    drive.unique,
+                )
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(breakdown, [("a", 2, 1, 2), ("b", 0, 1, 3), ("c", 0, 0, 0)]);
+        for ((_, root), drive) in drives.iter().zip(&redundancy.drives) {
+            assert_eq!(
+                drive.within_drive + drive.on_other_drives + drive.unique,
+                root.info().bytes
+            );
+        }
+        assert_eq!(redundancy.shared, [[3, 1, 0], [1, 4, 0], [0, 0, 0]]);
+    }
+}
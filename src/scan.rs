@@ -1,14 +1,19 @@
+mod archive;
+
 use std::{
-    collections::{BTreeMap, BTreeSet, HashSet},
-    fs::File,
+    cell::Cell,
+    collections::{BTreeMap, VecDeque},
+    fs::{DirEntry, File},
     hash::{BuildHasher, Hash, Hasher},
-    io::{BufRead, BufReader},
-    iter::once,
+    io::{self, Read, Seek, SeekFrom},
+    num::{NonZeroU64, NonZeroUsize},
     path::{Path, PathBuf},
     sync::{
-        Arc, Mutex,
-        atomic::{self, AtomicBool, AtomicU64},
+        Arc, Condvar, Mutex,
+        atomic::{self, AtomicBool, AtomicU64, AtomicUsize},
     },
+    thread,
+    time::{Duration, Instant},
 };
 
 use compact_str::CompactString;
@@ -16,6 +21,8 @@ use itertools::Itertools;
 use rayon::iter::{ParallelBridge, ParallelIterator};
 use serde::{Deserialize, Serialize};
 
+use crate::{scan::archive::ArchiveKind, tree::NodeId, utils::set_idle_io_priority};
+
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub enum Entry {
     Dir(Dir),
@@ -23,18 +30,31 @@ pub enum Entry {
 }
 
 impl Entry {
-    pub fn dir(entries: BTreeMap<CompactString, Entry>) -> Self {
-        Self::Dir(Dir {
-            info: EntryInfo::dir(entries.values().map(|entry| entry.info())),
-            dirs: 1 + entries.values().map(|entry| entry.dirs()).sum::<u64>(),
-            files: entries.values().map(|entry| entry.files()).sum(),
-            entries,
-        })
+    pub fn dir(entries: BTreeMap<CompactString, Entry>, dir_hashing: DirHashing) -> Self {
+        Self::Dir(Dir::new(entries, false, dir_hashing))
     }
 
+    /// Scans the given `path` recursively.
+    ///
+    /// If the scan is canceled, directories keep whatever was scanned up to that point and are
+    /// marked as [`Dir::partial`]. Such a partial scan can be continued using [`Entry::resume`].
     pub fn scan(path: impl AsRef<Path>, state: &ScanState) -> Option<Self> {
-        if state.canceled() {
-            return None;
+        Self::resume(path, None, state)
+    }
+
+    /// Like [`Entry::scan`], but reuses everything from a `previous` partial scan that was already
+    /// scanned completely.
+    ///
+    /// Files are only reused if their size still matches. Once the scan is canceled, whatever
+    /// wasn't visited yet is kept from `previous`, so that the result never has less in it.
+    pub fn resume(
+        path: impl AsRef<Path>,
+        previous: Option<Self>,
+        state: &ScanState,
+    ) -> Option<Self> {
+        if state.wait_while_paused() {
+            // partial directories stay partial
+            return previous;
         }
 
         let path = path.as_ref();
@@ -49,6 +69,24 @@ impl Entry {
             }
         };
 
+        let previous_entries = match previous {
+            Some(Self::File(info)) if metadata.is_file() && metadata.len() == info.bytes => {
+                state.add_files(1);
+                state.add_bytes(info.bytes);
+                return Some(Self::File(info));
+            }
+            Some(Self::Dir(dir)) if metadata.is_dir() => {
+                if !dir.partial {
+                    state.add_dirs(dir.dirs);
+                    state.add_files(dir.files);
+                    state.add_bytes(dir.info.bytes);
+                    return Some(Self::Dir(dir));
+                }
+                dir.entries
+            }
+            _ => BTreeMap::new(),
+        };
+
         if metadata.is_file() {
             let file = match File::open(path) {
                 Ok(file) => file,
@@ -58,56 +96,86 @@ impl Entry {
                 }
             };
 
-            let mut buf_reader = BufReader::new(file);
+            state.set_current_path(path);
+            let mut reader = ThrottledReader::new(file, metadata.len(), state);
+
+            if state.options.archives
+                && let Some(kind) = ArchiveKind::from_path(path)
+            {
+                match archive::scan(&mut reader, kind, path, state) {
+                    Ok(entry) => {
+                        state.add_files(1);
+                        return Some(entry);
+                    }
+                    Err(_) if state.canceled() => return None,
+                    Err(error) => {
+                        state.log(format!(
+                            "failed to read archive {}, hashing it as a file instead: {error}",
+                            path.display()
+                        ));
+                        if let Err(error) = reader.rewind() {
+                            state.log(format!("failed to rewind {}: {error}", path.display()));
+                            return None;
+                        }
+                    }
+                }
+            }
 
-            let mut hasher = FIXED_RANDOM_STATE.build_hasher();
-            let mut bytes = 0;
-            while let buf = match buf_reader.fill_buf() {
-                Ok(buf) => buf,
+            let info = match EntryInfo::file(&mut reader) {
+                Ok(info) => info,
+                Err(_) if state.canceled() => return None,
                 Err(error) => {
                     state.log(format!("failed to read {}: {error}", path.display()));
                     return None;
                 }
-            } && !buf.is_empty()
-            {
-                if state.canceled() {
-                    return None;
-                }
-
-                hasher.write(buf);
-                let buf_len = buf.len();
-                let buf_len_u64 = buf_len as u64;
-                bytes += buf_len_u64;
-                state.add_bytes(buf_len_u64);
-                buf_reader.consume(buf_len);
-            }
+            };
 
-            state.inc_files();
+            state.add_files(1);
 
-            Some(Self::File(EntryInfo {
-                kind: EntryKind::File,
-                bytes,
-                hash: hasher.finish(),
-            }))
+            Some(Self::File(info))
         } else if metadata.is_dir() {
-            let entries = path
+            let mut previous_entries = previous_entries;
+            let dir_entries = path
                 .read_dir()
                 .ok()?
                 .filter_map(|dir_entry| match dir_entry {
-                    Ok(dir_entry) => Some(dir_entry),
+                    Ok(dir_entry) => {
+                        let file_name =
+                            CompactString::from(dir_entry.file_name().to_string_lossy());
+                        let previous = previous_entries.remove(&file_name);
+                        Some((file_name, dir_entry, previous))
+                    }
                     Err(error) => {
                         state.log(format!("failed to read dir {}: {error}", path.display()));
                         None
                     }
-                })
-                .par_bridge()
-                .filter_map(|dir_entry| {
-                    let file_name = dir_entry.file_name().to_string_lossy().into();
-                    Some((file_name, Self::scan(dir_entry.path(), state)?))
-                })
-                .collect::<BTreeMap<_, _>>();
-            state.inc_dirs();
-            Some(Self::dir(entries))
+                });
+
+            let scan_entry = |(file_name, dir_entry, previous): (_, DirEntry, _)| {
+                Some((file_name, Self::resume(dir_entry.path(), previous, state)?))
+            };
+
+            let entries = if state.sequential {
+                let mut dir_entries = dir_entries.collect_vec();
+                sort_by_inode(&mut dir_entries);
+                dir_entries
+                    .into_iter()
+                    .filter_map(scan_entry)
+                    .collect::<BTreeMap<_, _>>()
+            } else {
+                dir_entries
+                    .par_bridge()
+                    .filter_map(scan_entry)
+                    .collect::<BTreeMap<_, _>>()
+            };
+            state.add_dirs(1);
+            // entries that were skipped due to cancellation cannot be told apart from entries that
+            // failed to scan, so everything that finishes after a cancellation counts as partial
+            Some(Self::Dir(Dir::new(
+                entries,
+                state.canceled(),
+                state.options.dir_hashing,
+            )))
         } else {
             state.log(format!("skipped (neither file/dir): {}", path.display()));
             None
@@ -135,104 +203,170 @@ impl Entry {
         }
     }
 
-    pub fn redundant_bytes(unfiltered_duplicates: &BTreeMap<EntryInfo, BTreeSet<PathBuf>>) -> u64 {
+    pub fn redundant_bytes(unfiltered_duplicates: &BTreeMap<EntryInfo, Vec<NodeId>>) -> u64 {
         unfiltered_duplicates
             .iter()
-            .filter(|(info, _)| (info.kind == EntryKind::File))
-            .map(|(info, paths)| info.bytes * (paths.len() as u64 - 1))
+            .filter(|(info, _)| info.kind == EntryKind::File)
+            .map(|(info, ids)| info.bytes * (ids.len() as u64 - 1))
             .sum::<u64>()
     }
 
-    /// Returns all sets of duplicate paths, keyed by their [`EntryInfo`].
-    ///
-    /// If a duplicate set is already implied by a higher-level set of duplicates, it is omitted.
-    ///
-    /// For example:
-    ///
-    /// - Given the sets `a/x.txt; b/y.txt` and `a; b`, the former is omitted, since `a; b` already
-    ///   implies that all of their contents match.
-    /// - However, `a/x.txt; b/y.txt; z.txt` is **not** omitted, since `z.txt` is not covered by the
-    ///   `a; b` prefix and therefore adds new information.
-    pub fn filter_duplicates_by_prefix(
-        mut duplicates: BTreeMap<EntryInfo, BTreeSet<PathBuf>>,
-    ) -> BTreeMap<EntryInfo, BTreeSet<PathBuf>> {
-        let prefixes = duplicates.values().cloned().collect::<HashSet<_>>();
-
-        duplicates.retain(|_, paths| {
-            let mut paths = paths.clone();
-            loop {
-                let Some(new_paths) = paths
-                    .into_iter()
-                    .map(|mut path| (path.pop() && path.file_name().is_some()).then_some(path))
-                    .collect()
-                else {
-                    // at least one path is now root and no prefix was found yet; keep this entry
-                    break true;
-                };
-
-                if prefixes.contains(&new_paths) {
-                    // this prefix already exists; remove it
-                    break false;
-                }
+    /// Keeps only the entries of [`Roots::grouped`](crate::tree::Roots::grouped) that exist more
+    /// than once.
+    pub fn unfiltered_duplicates(
+        mut grouped: BTreeMap<EntryInfo, Vec<NodeId>>,
+    ) -> BTreeMap<EntryInfo, Vec<NodeId>> {
+        grouped.retain(|_, ids| ids.len() > 1);
+        grouped
+    }
+}
 
-                paths = new_paths;
-            }
-        });
+/// Options that are chosen before a scan starts.
+///
+/// They are stored in the catalog, so that resumed scans and rescans use the same options.
+#[derive(Clone, Copy, Debug, Serialize, Deserialize)]
+pub struct ScanOptions {
+    /// Whether to [count](ScanState::count) files first, so that the progress is known.
+    pub count: bool,
+    /// Whether to scan the contents of zip and tar archives as if they were directories.
+    pub archives: bool,
+    pub dir_hashing: DirHashing,
+}
 
-        duplicates
+impl Default for ScanOptions {
+    fn default() -> Self {
+        Self {
+            count: true,
+            archives: false,
+            dir_hashing: DirHashing::Content,
+        }
     }
+}
 
-    pub fn unfiltered_duplicates(&self) -> BTreeMap<EntryInfo, BTreeSet<PathBuf>> {
-        self.hashes()
-            .into_grouping_map()
-            .collect::<BTreeSet<_>>()
-            .into_iter()
-            .filter(|(_, paths)| paths.len() > 1)
-            .collect::<BTreeMap<_, _>>()
-    }
-
-    fn hashes(&self) -> impl Iterator<Item = (EntryInfo, PathBuf)> + '_ {
-        self.hashes_with_root(PathBuf::new())
-    }
-
-    fn hashes_with_root(
-        &self,
-        path: PathBuf,
-    ) -> Box<dyn Iterator<Item = (EntryInfo, PathBuf)> + '_> {
-        Box::new(
-            once((self.info(), path.clone())).chain(
-                match self {
-                    Self::File(EntryInfo { .. }) => None,
-                    Self::Dir(dir) => Some(dir.entries.iter().map(move |(file_name, entry)| {
-                        let mut path = path.clone();
-                        path.push(file_name.clone());
-                        entry.hashes_with_root(path)
-                    })),
-                }
-                .into_iter()
-                .flatten()
-                .flatten(),
-            ),
-        )
-    }
+/// What makes two directories duplicates of each other.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum DirHashing {
+    /// Only the contents of files matter, regardless of their names.
+    #[default]
+    Content,
+    /// The names of all files and directories matter as well, so only identical structures match.
+    Names,
 }
 
 #[derive(Default)]
 pub struct ScanState {
+    options: ScanOptions,
+    /// Whether entries are scanned one by one in inode order instead of in parallel.
+    sequential: bool,
     canceled: AtomicBool,
+    paused: AtomicBool,
+    pause_lock: Mutex<()>,
+    unpaused: Condvar,
+    /// In bytes per second, `0` for unlimited.
+    bandwidth_limit: AtomicU64,
+    next_read: Mutex<Option<Instant>>,
+    /// `0` for unlimited.
+    thread_limit: AtomicUsize,
+    /// How many threads are currently reading files.
+    reading: Mutex<usize>,
+    reading_changed: Condvar,
+    idle_io_priority: AtomicBool,
     bytes: AtomicU64,
     dirs: AtomicU64,
     files: AtomicU64,
+    counting: AtomicBool,
+    counted: AtomicBool,
+    total_bytes: AtomicU64,
+    total_files: AtomicU64,
+    /// Recent `(time, bytes, files)` samples to calculate the throughput from.
+    samples: Mutex<VecDeque<(Instant, u64, u64)>>,
+    /// The file that was most recently started to be read.
+    current_path: Mutex<Option<PathBuf>>,
     error_log: Mutex<Vec<String>>,
 }
 
 impl ScanState {
-    pub fn new() -> Arc<Self> {
-        Arc::new(Self::default())
+    /// Creates the state for a scan with the given `options`.
+    ///
+    /// Sequential scans read entries one by one in inode order, which is the fastest way to read
+    /// from spinning disks.
+    pub fn new(options: ScanOptions, sequential: bool) -> Arc<Self> {
+        Arc::new(Self {
+            options,
+            sequential,
+            ..Default::default()
+        })
+    }
+
+    pub fn options(&self) -> ScanOptions {
+        self.options
+    }
+
+    pub fn sequential(&self) -> bool {
+        self.sequential
     }
 
+    /// Cancels the scan, which also stops waiting if it is paused or waiting for a thread.
     pub fn cancel(&self) {
+        // the locks ensure that waiting threads can't miss the notification
+        let _pause_guard = self.pause_lock.lock().unwrap();
+        let _reading_guard = self.reading.lock().unwrap();
         self.canceled.store(true, atomic::Ordering::Relaxed);
+        self.unpaused.notify_all();
+        self.reading_changed.notify_all();
+    }
+
+    pub fn paused(&self) -> bool {
+        self.paused.load(atomic::Ordering::Relaxed)
+    }
+
+    /// Pauses or resumes the scan.
+    ///
+    /// While paused, all threads of the scan wait before scanning the next entry or reading the
+    /// next chunk of a file.
+    pub fn set_paused(&self, paused: bool) {
+        let _guard = self.pause_lock.lock().unwrap();
+        self.paused.store(paused, atomic::Ordering::Relaxed);
+        if !paused {
+            self.unpaused.notify_all();
+        }
+    }
+
+    /// The maximum number of bytes per second that are read across all threads of this scan.
+    pub fn bandwidth_limit(&self) -> Option<NonZeroU64> {
+        NonZeroU64::new(self.bandwidth_limit.load(atomic::Ordering::Relaxed))
+    }
+
+    pub fn set_bandwidth_limit(&self, bandwidth_limit: Option<NonZeroU64>) {
+        *self.next_read.lock().unwrap() = None;
+        self.bandwidth_limit.store(
+            bandwidth_limit.map_or(0, NonZeroU64::get),
+            atomic::Ordering::Relaxed,
+        );
+    }
+
+    /// The maximum number of threads that read files at the same time.
+    pub fn thread_limit(&self) -> Option<NonZeroUsize> {
+        NonZeroUsize::new(self.thread_limit.load(atomic::Ordering::Relaxed))
+    }
+
+    pub fn set_thread_limit(&self, thread_limit: Option<NonZeroUsize>) {
+        let _guard = self.reading.lock().unwrap();
+        self.thread_limit.store(
+            thread_limit.map_or(0, NonZeroUsize::get),
+            atomic::Ordering::Relaxed,
+        );
+        self.reading_changed.notify_all();
+    }
+
+    /// Whether threads read files with idle I/O priority, which is only supported on Linux.
+    pub fn idle_io_priority(&self) -> bool {
+        self.idle_io_priority.load(atomic::Ordering::Relaxed)
+    }
+
+    pub fn set_idle_io_priority(&self, idle_io_priority: bool) {
+        self.idle_io_priority
+            .store(idle_io_priority, atomic::Ordering::Relaxed);
     }
 
     pub fn bytes(&self) -> u64 {
@@ -247,6 +381,92 @@ impl ScanState {
         self.files.load(atomic::Ordering::Relaxed)
     }
 
+    /// Counts the files and bytes below `path` from metadata alone, without reading any files.
+    ///
+    /// This is a lot quicker than the actual scan and allows [`ScanState::progress`] to calculate
+    /// how far along the scan is. Unreadable entries are skipped, since the scan logs them anyway.
+    pub fn count(&self, path: &Path) {
+        self.counting.store(true, atomic::Ordering::Relaxed);
+        self.count_recursive(path);
+        self.counting.store(false, atomic::Ordering::Relaxed);
+        self.counted
+            .store(!self.canceled(), atomic::Ordering::Relaxed);
+    }
+
+    /// Returns how many files were counted so far while [`ScanState::count`] is running.
+    pub fn counting(&self) -> Option<u64> {
+        self.counting
+            .load(atomic::Ordering::Relaxed)
+            .then(|| self.total_files.load(atomic::Ordering::Relaxed))
+    }
+
+    /// Returns the progress of the scan, which is only partially available if the scan was not
+    /// [counted](ScanState::count) beforehand.
+    pub fn progress(&self) -> Progress {
+        let now = Instant::now();
+        let bytes = self.bytes();
+        let files = self.files();
+
+        let (bytes_per_second, files_per_second) = {
+            let mut samples = self.samples.lock().unwrap();
+            while samples
+                .get(1)
+                .is_some_and(|(time, ..)| now - *time > THROUGHPUT_WINDOW)
+            {
+                samples.pop_front();
+            }
+            samples.push_back((now, bytes, files));
+
+            let (time, old_bytes, old_files) = samples[0];
+            let seconds = (now - time).as_secs_f64();
+            if seconds > 0.0 {
+                (
+                    (bytes - old_bytes) as f64 / seconds,
+                    (files - old_files) as f64 / seconds,
+                )
+            } else {
+                (0.0, 0.0)
+            }
+        };
+
+        let counted = self.counted.load(atomic::Ordering::Relaxed);
+        let total_bytes = self.total_bytes.load(atomic::Ordering::Relaxed);
+        let total_files = self.total_files.load(atomic::Ordering::Relaxed);
+
+        // files can change between counting and scanning, so this is only an estimate
+        let fraction = counted.then(|| {
+            let fraction = if total_bytes > 0 {
+                bytes as f32 / total_bytes as f32
+            } else if total_files > 0 {
+                files as f32 / total_files as f32
+            } else {
+                1.0
+            };
+            fraction.min(1.0)
+        });
+
+        // based on bytes, unless nothing but empty files were read recently
+        let remaining = [
+            (total_bytes.saturating_sub(bytes), bytes_per_second),
+            (total_files.saturating_sub(files), files_per_second),
+        ]
+        .into_iter()
+        .find(|(_, per_second)| *per_second > 0.0)
+        .filter(|_| counted)
+        .map(|(left, per_second)| Duration::from_secs_f64(left as f64 / per_second));
+
+        Progress {
+            fraction,
+            bytes_per_second,
+            files_per_second,
+            remaining,
+        }
+    }
+
+    pub fn current_path(&self) -> Option<PathBuf> {
+        self.current_path.lock().unwrap().clone()
+    }
+
     /// Returns the last error and how many additional errors there were.
     pub fn last_error_plus(&self) -> Option<(String, usize)> {
         let error_log = self.error_log.lock().unwrap();
@@ -261,12 +481,45 @@ impl ScanState {
         self.bytes.fetch_add(bytes, atomic::Ordering::Relaxed);
     }
 
-    fn inc_dirs(&self) {
-        self.dirs.fetch_add(1, atomic::Ordering::Relaxed);
+    fn add_dirs(&self, dirs: u64) {
+        self.dirs.fetch_add(dirs, atomic::Ordering::Relaxed);
     }
 
-    fn inc_files(&self) {
-        self.files.fetch_add(1, atomic::Ordering::Relaxed);
+    fn add_files(&self, files: u64) {
+        self.files.fetch_add(files, atomic::Ordering::Relaxed);
+    }
+
+    fn count_recursive(&self, path: &Path) {
+        if self.wait_while_paused() {
+            return;
+        }
+
+        let Ok(metadata) = path.symlink_metadata() else {
+            return;
+        };
+
+        if metadata.is_file() {
+            self.total_files.fetch_add(1, atomic::Ordering::Relaxed);
+            self.total_bytes
+                .fetch_add(metadata.len(), atomic::Ordering::Relaxed);
+        } else if metadata.is_dir() {
+            let Ok(read_dir) = path.read_dir() else {
+                return;
+            };
+
+            let paths = read_dir.filter_map(|dir_entry| Some(dir_entry.ok()?.path()));
+            if self.sequential {
+                paths.for_each(|path| self.count_recursive(&path));
+            } else {
+                paths
+                    .par_bridge()
+                    .for_each(|path| self.count_recursive(&path));
+            }
+        }
+    }
+
+    fn set_current_path(&self, path: &Path) {
+        *self.current_path.lock().unwrap() = Some(path.to_path_buf());
     }
 
     fn log(&self, message: String) {
@@ -276,6 +529,191 @@ impl ScanState {
     fn canceled(&self) -> bool {
         self.canceled.load(atomic::Ordering::Relaxed)
     }
+
+    /// Blocks for as long as reading `bytes` takes with the current bandwidth limit.
+    fn limit_bandwidth(&self, bytes: u64) {
+        if let Some(until) = self.reserve_bandwidth(bytes, Instant::now()) {
+            thread::sleep(until.saturating_duration_since(Instant::now()));
+        }
+    }
+
+    /// Reserves a time slot for reading `bytes` after all previous reads and returns when it
+    /// ends, or `None` if the bandwidth isn't limited.
+    fn reserve_bandwidth(&self, bytes: u64, now: Instant) -> Option<Instant> {
+        let bandwidth_limit = self.bandwidth_limit()?;
+        let mut next_read = self.next_read.lock().unwrap();
+        let start = next_read
+            .filter(|next_read| *next_read > now)
+            .unwrap_or(now);
+        let until = start + Duration::from_secs_f64(bytes as f64 / bandwidth_limit.get() as f64);
+        *next_read = Some(until);
+        Some(until)
+    }
+
+    /// Blocks while the scan is paused and returns whether it was canceled.
+    fn wait_while_paused(&self) -> bool {
+        if self.paused() {
+            let guard = self.pause_lock.lock().unwrap();
+            let _guard = self
+                .unpaused
+                .wait_while(guard, |_| self.paused() && !self.canceled())
+                .unwrap();
+        }
+        self.canceled()
+    }
+}
+
+/// How far back [`ScanState::progress`] looks to calculate the current throughput.
+const THROUGHPUT_WINDOW: Duration = Duration::from_secs(5);
+
+pub struct Progress {
+    /// How much of the counted total was scanned already, between `0` and `1`.
+    pub fraction: Option<f32>,
+    pub bytes_per_second: f64,
+    pub files_per_second: f64,
+    /// The estimated time until the scan finishes, based on the counted total.
+    pub remaining: Option<Duration>,
+}
+
+/// Sorts directory entries by their inode, which roughly matches their order on disk.
+#[cfg(unix)]
+fn sort_by_inode<T, U>(dir_entries: &mut [(T, DirEntry, U)]) {
+    use std::os::unix::fs::DirEntryExt;
+
+    dir_entries.sort_by_key(|(_, dir_entry, _)| dir_entry.ino());
+}
+
+#[cfg(not(unix))]
+fn sort_by_inode<T, U>(_dir_entries: &mut [(T, DirEntry, U)]) {}
+
+/// Applies the limits of a [`ScanState`] to every read and keeps track of the read bytes.
+///
+/// Returns an error once the scan is canceled.
+struct ThrottledReader<'a, R> {
+    inner: R,
+    read_slot: ReadSlot<'a>,
+    /// The bytes that are still to be counted and limited.
+    ///
+    /// This starts at the size of the file, so that bytes that are read more than once, e.g. when
+    /// parsing an archive seeks around or fails and the file is hashed instead, only count once.
+    uncounted: u64,
+}
+
+impl<'a, R> ThrottledReader<'a, R> {
+    fn new(inner: R, bytes: u64, state: &'a ScanState) -> Self {
+        Self {
+            inner,
+            read_slot: ReadSlot::new(state),
+            uncounted: bytes,
+        }
+    }
+}
+
+impl<R: Read> Read for ThrottledReader<'_, R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if !self.read_slot.wait() {
+            return Err(io::Error::other("scan canceled"));
+        }
+
+        let len = self.inner.read(buf)?;
+        let counted = self.uncounted.min(len as u64);
+        self.uncounted -= counted;
+
+        let state = self.read_slot.state;
+        state.add_bytes(counted);
+        state.limit_bandwidth(counted);
+        Ok(len)
+    }
+}
+
+impl<R: Seek> Seek for ThrottledReader<'_, R> {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        self.inner.seek(pos)
+    }
+}
+
+/// Permission for a thread to read a file, limited by [`ScanState::thread_limit`].
+struct ReadSlot<'a> {
+    state: &'a ScanState,
+    acquired: bool,
+}
+
+impl<'a> ReadSlot<'a> {
+    fn new(state: &'a ScanState) -> Self {
+        Self {
+            state,
+            acquired: false,
+        }
+    }
+
+    /// Waits until the next chunk may be read and returns `false` if the scan was canceled instead.
+    ///
+    /// Since this is called before every chunk, changes to the scan state apply immediately, even
+    /// while large files are being read.
+    fn wait(&mut self) -> bool {
+        let state = self.state;
+        if state.wait_while_paused() {
+            return false;
+        }
+
+        if !self.acquired || state.thread_limit().is_some() {
+            let over_limit = |reading: &mut usize| {
+                state
+                    .thread_limit()
+                    .is_some_and(|thread_limit| *reading >= thread_limit.get())
+            };
+
+            let mut reading = state.reading.lock().unwrap();
+            if self.acquired {
+                // let other threads catch up if the limit was lowered
+                *reading -= 1;
+                if over_limit(&mut reading) {
+                    self.acquired = false;
+                    state.reading_changed.notify_all();
+                } else {
+                    *reading += 1;
+                }
+            }
+
+            if !self.acquired {
+                reading = state
+                    .reading_changed
+                    .wait_while(reading, |reading| over_limit(reading) && !state.canceled())
+                    .unwrap();
+                if state.canceled() {
+                    return false;
+                }
+                *reading += 1;
+                self.acquired = true;
+            }
+        }
+
+        let idle_io_priority = state.idle_io_priority();
+        if IDLE_IO_PRIORITY.replace(idle_io_priority) != idle_io_priority
+            && let Err(error) = set_idle_io_priority(idle_io_priority)
+        {
+            state.log(format!("failed to change I/O priority: {error}"));
+        }
+
+        true
+    }
+}
+
+impl Drop for ReadSlot<'_> {
+    fn drop(&mut self) {
+        if self.acquired {
+            *self.state.reading.lock().unwrap() -= 1;
+            self.state.reading_changed.notify_all();
+        }
+    }
+}
+
+thread_local! {
+    /// Whether the current thread has idle I/O priority.
+    ///
+    /// Threads are shared between scans, so this is updated whenever a thread starts reading for a
+    /// scan with a different setting.
+    static IDLE_IO_PRIORITY: Cell<bool> = const { Cell::new(false) };
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
@@ -284,6 +722,32 @@ pub struct Dir {
     pub dirs: u64,
     pub files: u64,
     pub entries: BTreeMap<CompactString, Entry>,
+    /// Whether the scan of this directory was canceled before it finished.
+    ///
+    /// The [`EntryInfo`] of partial directories only covers what was scanned, so they are never
+    /// reported as duplicates. Their parents are always partial as well.
+    pub partial: bool,
+}
+
+impl Dir {
+    fn new(
+        entries: BTreeMap<CompactString, Entry>,
+        partial: bool,
+        dir_hashing: DirHashing,
+    ) -> Self {
+        Self {
+            info: EntryInfo::dir(
+                entries
+                    .iter()
+                    .map(|(file_name, entry)| (file_name.as_str(), entry.info())),
+                dir_hashing,
+            ),
+            dirs: 1 + entries.values().map(|entry| entry.dirs()).sum::<u64>(),
+            files: entries.values().map(|entry| entry.files()).sum(),
+            entries,
+            partial,
+        }
+    }
 }
 
 #[derive(Clone, Copy, Debug, Hash, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
@@ -294,15 +758,54 @@ pub struct EntryInfo {
 }
 
 impl EntryInfo {
-    fn dir(entries: impl Iterator<Item = Self> + Clone) -> Self {
-        let mut hashes = entries.clone().map(|x| x.hash).collect_vec();
-        // sort hashes so that the order of hashes (order of files) doesn't matter
-        hashes.sort();
+    /// Hashes all contents of a file, which might also be a file inside an archive.
+    fn file(reader: &mut impl Read) -> io::Result<Self> {
+        let mut hasher = FIXED_RANDOM_STATE.build_hasher();
+        let mut buf = [0; CHUNK_SIZE];
+        let mut bytes = 0;
+        loop {
+            let len = read_chunk(reader, &mut buf)?;
+            if len == 0 {
+                break;
+            }
+            hasher.write(&buf[..len]);
+            bytes += len as u64;
+        }
+
+        Ok(Self {
+            kind: EntryKind::File,
+            bytes,
+            hash: hasher.finish(),
+        })
+    }
+
+    /// Combines the infos of the entries of a directory, which have to be sorted by name.
+    pub fn dir<'a>(
+        entries: impl Iterator<Item = (&'a str, EntryInfo)> + Clone,
+        dir_hashing: DirHashing,
+    ) -> Self {
+        let infos = entries.clone().map(|(_, info)| info);
+        let hash = match dir_hashing {
+            DirHashing::Content => {
+                let mut hashes = infos.clone().map(|x| x.hash).collect_vec();
+                // sort hashes so that the order of hashes (order of files) doesn't matter
+                hashes.sort();
+                // marker to prevent empty directories from leading to the same hash as empty files
+                FIXED_RANDOM_STATE.hash_one((hashes, 0xBEEE38829F9F8197_u64))
+            }
+            DirHashing::Names => {
+                let hashes = entries
+                    .map(|(file_name, info)| (file_name, info.hash))
+                    .collect_vec();
+                // a different marker, so that directories never match across both modes
+                FIXED_RANDOM_STATE.hash_one((hashes, 0x5D1A40E3C2B7F96E_u64))
+            }
+        };
+
         Self {
             kind: EntryKind::Dir,
-            bytes: entries.map(|x| x.bytes).sum(),
-            // marker to prevent empty directories from leading to the same hash as empty files
-            hash: FIXED_RANDOM_STATE.hash_one((hashes, 0xBEEE38829F9F8197_u64)),
+            bytes: infos.map(|x| x.bytes).sum(),
+            hash,
         }
     }
 }
@@ -313,4 +816,291 @@ pub enum EntryKind {
     File,
 }
 
+/// The size of the chunks that file contents are hashed in.
+///
+/// Hashes depend on how the contents are split up, so this must never change. It matches the
+/// default capacity of [`BufReader`](std::io::BufReader), which files used to be read with.
+const CHUNK_SIZE: usize = 8 * 1024;
+
+/// Fills `buf` as far as possible, so that only the last chunk of a file can be smaller.
+fn read_chunk(reader: &mut impl Read, buf: &mut [u8]) -> io::Result<usize> {
+    let mut len = 0;
+    while len < buf.len() {
+        match reader.read(&mut buf[len..]) {
+            Ok(0) => break,
+            Ok(read) => len += read,
+            Err(error) if error.kind() == io::ErrorKind::Interrupted => {}
+            Err(error) => return Err(error),
+        }
+    }
+    Ok(len)
+}
+
 const FIXED_RANDOM_STATE: ahash::RandomState = ahash::RandomState::with_seeds(0, 0, 0, 0);
+
+#[cfg(test)]
+pub mod tests {
+    use std::fs;
+
+    use super::*;
+    use crate::tree::{Roots, Tree};
+
+    /// A file whose size and content are both given by `bytes`, so that files only match if they
+    /// have the same size.
+    pub fn file(bytes: u64) -> Entry {
+        Entry::File(EntryInfo {
+            bytes,
+            kind: EntryKind::File,
+            hash: bytes,
+        })
+    }
+
+    pub fn dir<N: Into<CompactString>>(entries: impl IntoIterator<Item = (N, Entry)>) -> Entry {
+        Entry::dir(
+            entries
+                .into_iter()
+                .map(|(name, entry)| (name.into(), entry))
+                .collect(),
+            DirHashing::Content,
+        )
+    }
+
+    pub fn roots<N: Into<CompactString>>(drives: impl IntoIterator<Item = (N, Entry)>) -> Roots {
+        drives
+            .into_iter()
+            .map(|(name, entry)| (name.into(), Arc::new(Tree::new(entry))))
+            .collect()
+    }
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("ssdedupe-scan-{name}-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn partial_dir<N: Into<CompactString>>(entries: impl IntoIterator<Item = (N, Entry)>) -> Entry {
+        let entries = entries
+            .into_iter()
+            .map(|(name, entry)| (name.into(), entry))
+            .collect();
+        Entry::Dir(Dir::new(entries, true, DirHashing::Content))
+    }
+
+    fn get<'a>(entry: &'a Entry, path: &str) -> &'a Entry {
+        Path::new(path).iter().fold(entry, |entry, file_name| {
+            let Entry::Dir(dir) = entry else {
+                panic!("{path} should be inside a folder");
+            };
+            &dir.entries[file_name.to_str().unwrap()]
+        })
+    }
+
+    /// A partial scan of `root/{done/{a,b},todo/{c,d}}` that only finished `done` and found `c`
+    /// and `d` with the wrong size. Their hashes only match if they aren't read again.
+    fn resumable(temp_dir: &Path) -> (PathBuf, Entry) {
+        let root = temp_dir.join("root");
+        fs::create_dir_all(root.join("done")).unwrap();
+        fs::create_dir_all(root.join("todo")).unwrap();
+        fs::write(root.join("done/a"), "a").unwrap();
+        fs::write(root.join("done/b"), "bb").unwrap();
+        fs::write(root.join("todo/c"), "ccc").unwrap();
+        fs::write(root.join("todo/d"), "dddd").unwrap();
+
+        let previous = partial_dir([
+            ("done", dir([("a", file(1)), ("b", file(2))])),
+            ("todo", partial_dir([("c", file(3)), ("d", file(1))])),
+        ]);
+        (root, previous)
+    }
+
+    #[test]
+    fn resumed_scans_reuse_finished_parts() {
+        let temp_dir = temp_dir("resume");
+        let (root, previous) = resumable(&temp_dir);
+
+        let state = ScanState::new(ScanOptions::default(), false);
+        let entry = Entry::resume(&root, Some(previous), &state).unwrap();
+        let scanned = Entry::scan(&root, &ScanState::new(ScanOptions::default(), false)).unwrap();
+        fs::remove_dir_all(&temp_dir).unwrap();
+
+        // finished folders aren't read again, not even to check their files
+        assert_eq!(get(&entry, "done/a").info(), file(1).info());
+        assert_eq!(get(&entry, "done/b").info(), file(2).info());
+        // files of partial folders are only read again if their size changed
+        assert_eq!(get(&entry, "todo/c").info(), file(3).info());
+        assert_eq!(get(&entry, "todo/d").info(), get(&scanned, "todo/d").info());
+        assert_ne!(get(&entry, "todo/d").info(), file(1).info());
+        let Entry::Dir(dir) = &entry else {
+            panic!("root should be a folder");
+        };
+        assert!(!dir.partial);
+        assert_eq!(entry.files(), 4);
+        assert_eq!(state.files(), 4);
+    }
+
+    #[test]
+    fn canceled_resumed_scans_keep_previous_progress() {
+        let temp_dir = temp_dir("resume-canceled");
+        let (root, previous) = resumable(&temp_dir);
+
+        let state = ScanState::new(ScanOptions::default(), false);
+        state.cancel();
+        let entry = Entry::resume(&root, Some(previous.clone()), &state).unwrap();
+        fs::remove_dir_all(&temp_dir).unwrap();
+
+        let Entry::Dir(dir) = &entry else {
+            panic!("root should be a folder");
+        };
+        assert!(dir.partial);
+        assert_eq!(entry.info(), previous.info());
+        assert_eq!(get(&entry, "done/a").info(), file(1).info());
+        assert_eq!(get(&entry, "done/b").info(), file(2).info());
+        assert_eq!(get(&entry, "todo/d").info(), file(1).info());
+    }
+
+    /// Pretends the scan started two seconds ago, so that the throughput is known.
+    fn started_two_seconds_ago(state: &ScanState) {
+        let started = Instant::now() - Duration::from_secs(2);
+        state.samples.lock().unwrap().push_back((started, 0, 0));
+    }
+
+    #[test]
+    fn progress_is_estimated_from_the_counted_total() {
+        let temp_dir = temp_dir("progress");
+        fs::write(temp_dir.join("a"), [0; 100]).unwrap();
+        fs::create_dir(temp_dir.join("dir")).unwrap();
+        fs::write(temp_dir.join("dir/b"), [0; 300]).unwrap();
+        let state = ScanState::new(ScanOptions::default(), false);
+        state.count(&temp_dir);
+        fs::remove_dir_all(&temp_dir).unwrap();
+
+        started_two_seconds_ago(&state);
+        state.add_bytes(100);
+        state.add_files(1);
+        let progress = state.progress();
+        assert_eq!(progress.fraction, Some(0.25));
+        assert!((progress.bytes_per_second - 50.0).abs() < 1.0);
+        // 300 bytes left at 50 bytes per second
+        let remaining = progress.remaining.unwrap().as_secs_f64();
+        assert!((remaining - 6.0).abs() < 0.1, "{remaining}");
+
+        // files that grew since counting don't go past the end
+        state.add_bytes(1000);
+        assert_eq!(state.progress().fraction, Some(1.0));
+        assert_eq!(state.progress().remaining, Some(Duration::ZERO));
+    }
+
+    #[test]
+    fn progress_of_empty_files_is_estimated_from_their_count() {
+        let state = ScanState::new(ScanOptions::default(), false);
+        state.counted.store(true, atomic::Ordering::Relaxed);
+        state.total_files.store(4, atomic::Ordering::Relaxed);
+
+        started_two_seconds_ago(&state);
+        state.add_files(1);
+        let progress = state.progress();
+        assert_eq!(progress.fraction, Some(0.25));
+        assert_eq!(progress.bytes_per_second, 0.0);
+        // 3 files left at half a file per second
+        let remaining = progress.remaining.unwrap().as_secs_f64();
+        assert!((remaining - 6.0).abs() < 0.1, "{remaining}");
+    }
+
+    #[test]
+    fn progress_without_counting_only_has_the_throughput() {
+        let state = ScanState::new(ScanOptions::default(), false);
+        started_two_seconds_ago(&state);
+        state.add_bytes(100);
+        state.add_files(1);
+        let progress = state.progress();
+        assert_eq!(progress.fraction, None);
+        assert_eq!(progress.remaining, None);
+        assert!((progress.bytes_per_second - 50.0).abs() < 1.0);
+        assert!((progress.files_per_second - 0.5).abs() < 0.01);
+    }
+
+    #[test]
+    fn reads_are_spread_out_to_the_bandwidth_limit() {
+        let state = ScanState::new(ScanOptions::default(), false);
+        let now = Instant::now();
+        assert_eq!(state.reserve_bandwidth(1000, now), None);
+
+        state.set_bandwidth_limit(NonZeroU64::new(1000));
+        let after = |millis| now + Duration::from_millis(millis);
+        assert_eq!(state.reserve_bandwidth(500, now), Some(after(500)));
+        // reads of other threads at the same time have to wait for the previous ones
+        assert_eq!(state.reserve_bandwidth(250, now), Some(after(750)));
+        assert_eq!(state.reserve_bandwidth(0, after(100)), Some(after(750)));
+        // time without reads isn't saved up for later
+        assert_eq!(
+            state.reserve_bandwidth(1000, after(2000)),
+            Some(after(3000))
+        );
+
+        // a new limit applies right away
+        state.set_bandwidth_limit(NonZeroU64::new(2000));
+        assert_eq!(state.reserve_bandwidth(1000, now), Some(after(500)));
+        state.set_bandwidth_limit(None);
+        assert_eq!(state.reserve_bandwidth(1000, now), None);
+    }
+
+    #[test]
+    fn read_slots_are_limited_to_the_thread_limit() {
+        let state = ScanState::new(ScanOptions::default(), false);
+        state.set_thread_limit(NonZeroUsize::new(2));
+        let max_reading = AtomicUsize::new(0);
+        thread::scope(|scope| {
+            for _ in 0..8 {
+                scope.spawn(|| {
+                    let mut read_slot = ReadSlot::new(&state);
+                    for _ in 0..10 {
+                        assert!(read_slot.wait());
+                        let reading = *state.reading.lock().unwrap();
+                        max_reading.fetch_max(reading, atomic::Ordering::Relaxed);
+                        thread::sleep(Duration::from_millis(1));
+                    }
+                });
+            }
+        });
+        assert!(max_reading.into_inner() <= 2);
+        assert_eq!(*state.reading.lock().unwrap(), 0);
+    }
+
+    #[test]
+    fn waiting_for_a_read_slot_stops_when_canceled() {
+        let state = ScanState::new(ScanOptions::default(), false);
+        state.set_thread_limit(NonZeroUsize::new(1));
+        let mut read_slot = ReadSlot::new(&state);
+        assert!(read_slot.wait());
+
+        thread::scope(|scope| {
+            let waiting = scope.spawn(|| ReadSlot::new(&state).wait());
+            thread::sleep(Duration::from_millis(50));
+            assert!(!waiting.is_finished());
+            state.cancel();
+            assert!(!waiting.join().unwrap());
+        });
+        assert!(!read_slot.wait());
+    }
+
+    #[test]
+    fn broken_archive_is_counted_once() {
+        let dir = std::env::temp_dir().join(format!("ssdedupe-scan-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("broken.zip");
+        let content = vec![b'x'; 3 * CHUNK_SIZE];
+        fs::write(&path, &content).unwrap();
+
+        let options = ScanOptions {
+            archives: true,
+            ..Default::default()
+        };
+        let state = ScanState::new(options, false);
+        let entry = Entry::scan(&path, &state);
+        fs::remove_dir_all(&dir).unwrap();
+
+        assert!(matches!(entry, Some(Entry::File(info)) if info.bytes == content.len() as u64));
+        assert_eq!(state.bytes(), content.len() as u64);
+    }
+}
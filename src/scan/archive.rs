@@ -0,0 +1,341 @@
+use std::{
+    collections::BTreeMap,
+    io::{self, BufReader, Read, Seek},
+    path::{Component, Path},
+};
+
+use compact_str::CompactString;
+use flate2::read::GzDecoder;
+use tar::EntryType;
+use zip::ZipArchive;
+
+use super::{DirHashing, Entry, EntryInfo, ScanState};
+
+/// Archives are read in small pieces, so they are buffered to not throttle every single read.
+const BUFFER_SIZE: usize = 64 * 1024;
+
+#[derive(Clone, Copy, Debug)]
+pub enum ArchiveKind {
+    Zip,
+    Tar,
+    TarGz,
+}
+
+impl ArchiveKind {
+    /// Determines the kind of archive from the extension of `path`.
+    pub fn from_path(path: &Path) -> Option<Self> {
+        let file_name = path.file_name()?.to_str()?.to_ascii_lowercase();
+        if file_name.ends_with(".zip") {
+            Some(Self::Zip)
+        } else if file_name.ends_with(".tar") {
+            Some(Self::Tar)
+        } else if file_name.ends_with(".tar.gz") || file_name.ends_with(".tgz") {
+            Some(Self::TarGz)
+        } else {
+            None
+        }
+    }
+}
+
+/// Scans the contents of the archive at `path` into a virtual directory.
+///
+/// Files are hashed the same way as files on disk, so they can match each other. Entries that
+/// cannot be read are logged and skipped, just like on disk.
+pub fn scan(
+    reader: impl Read + Seek,
+    kind: ArchiveKind,
+    path: &Path,
+    state: &ScanState,
+) -> io::Result<Entry> {
+    let reader = BufReader::with_capacity(BUFFER_SIZE, reader);
+    let mut root = VirtualDir::default();
+    match kind {
+        ArchiveKind::Zip => scan_zip(reader, &mut root, path, state)?,
+        ArchiveKind::Tar => scan_tar(reader, &mut root)?,
+        ArchiveKind::TarGz => scan_tar(GzDecoder::new(reader), &mut root)?,
+    }
+    Ok(root.into_entry(state.options.dir_hashing))
+}
+
+fn scan_zip(
+    reader: impl Read + Seek,
+    root: &mut VirtualDir,
+    path: &Path,
+    state: &ScanState,
+) -> io::Result<()> {
+    let mut archive = ZipArchive::new(reader)?;
+    for index in 0..archive.len() {
+        let mut file = match archive.by_index(index) {
+            Ok(file) => file,
+            Err(error) => {
+                if state.canceled() {
+                    return Err(error.into());
+                }
+                state.log(format!(
+                    "failed to read entry #{index} of {}: {error}",
+                    path.display()
+                ));
+                continue;
+            }
+        };
+
+        let Some(name) = file.enclosed_name() else {
+            state.log(format!(
+                "skipped entry outside of archive: {} in {}",
+                file.name(),
+                path.display()
+            ));
+            continue;
+        };
+
+        if file.is_dir() {
+            root.insert(&name, None);
+            continue;
+        }
+
+        match EntryInfo::file(&mut file) {
+            Ok(info) => root.insert(&name, Some(info)),
+            Err(error) => {
+                if state.canceled() {
+                    return Err(error);
+                }
+                state.log(format!(
+                    "failed to read {} in {}: {error}",
+                    name.display(),
+                    path.display()
+                ));
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Unlike zip files, tar files are a single stream, so any error is fatal.
+fn scan_tar(reader: impl Read, root: &mut VirtualDir) -> io::Result<()> {
+    for entry in tar::Archive::new(reader).entries()? {
+        let mut entry = entry?;
+        let name = entry.path()?.into_owned();
+        match entry.header().entry_type() {
+            EntryType::Directory => root.insert(&name, None),
+            EntryType::Regular | EntryType::Continuous => {
+                root.insert(&name, Some(EntryInfo::file(&mut entry)?))
+            }
+            // links and special files are skipped on disk as well
+            _ => {}
+        }
+    }
+    Ok(())
+}
+
+/// A directory inside an archive, which is built up from the archive's paths in any order.
+#[derive(Default)]
+struct VirtualDir {
+    entries: BTreeMap<CompactString, VirtualEntry>,
+}
+
+enum VirtualEntry {
+    Dir(VirtualDir),
+    File(EntryInfo),
+}
+
+impl VirtualDir {
+    /// Inserts a file or, if `info` is `None`, a directory, creating any missing parents.
+    ///
+    /// Paths that could leave the archive are ignored.
+    fn insert(&mut self, path: &Path, info: Option<EntryInfo>) {
+        let mut file_names = Vec::new();
+        for component in path.components() {
+            match component {
+                Component::Normal(file_name) => {
+                    file_names.push(CompactString::from(file_name.to_string_lossy()))
+                }
+                // absolute paths are treated as relative to the archive
+                Component::Prefix(_) | Component::RootDir | Component::CurDir => {}
+                Component::ParentDir => return,
+            }
+        }
+
+        let Some((file_name, parents)) = file_names.split_last() else {
+            return;
+        };
+
+        let mut dir = self;
+        for parent in parents {
+            dir = match dir
+                .entries
+                .entry(parent.clone())
+                .or_insert_with(|| VirtualEntry::Dir(Default::default()))
+            {
+                VirtualEntry::Dir(dir) => dir,
+                // a file with the same name as a directory; keep whichever came first
+                VirtualEntry::File(_) => return,
+            };
+        }
+
+        // keep whichever came first, so that a file can't replace a directory with its contents
+        dir.entries
+            .entry(file_name.clone())
+            .or_insert_with(|| match info {
+                Some(info) => VirtualEntry::File(info),
+                None => VirtualEntry::Dir(Default::default()),
+            });
+    }
+
+    fn into_entry(self, dir_hashing: DirHashing) -> Entry {
+        Entry::dir(
+            self.entries
+                .into_iter()
+                .map(|(file_name, entry)| {
+                    let entry = match entry {
+                        VirtualEntry::Dir(dir) => dir.into_entry(dir_hashing),
+                        VirtualEntry::File(info) => Entry::File(info),
+                    };
+                    (file_name, entry)
+                })
+                .collect(),
+            dir_hashing,
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        fs,
+        io::{Cursor, Write},
+        path::PathBuf,
+        sync::atomic::AtomicBool,
+    };
+
+    use flate2::write::GzEncoder;
+    use zip::{ZipWriter, write::SimpleFileOptions};
+
+    use super::*;
+    use crate::scan::{
+        EntryKind, ScanOptions,
+        tests::{file, roots},
+    };
+
+    const SAME: &[u8] = b"the same content inside and outside of archives";
+
+    fn files() -> [(&'static str, &'static [u8]); 3] {
+        [
+            ("inner/same.txt", SAME),
+            ("inner/deeper/other.txt", b"other"),
+            ("top.txt", b"top"),
+        ]
+    }
+
+    fn zip() -> Vec<u8> {
+        let mut writer = ZipWriter::new(Cursor::new(Vec::new()));
+        writer
+            .add_directory("empty/", SimpleFileOptions::default())
+            .unwrap();
+        for (name, data) in files() {
+            writer
+                .start_file(name, SimpleFileOptions::default())
+                .unwrap();
+            writer.write_all(data).unwrap();
+        }
+        writer.finish().unwrap().into_inner()
+    }
+
+    fn tar() -> Vec<u8> {
+        let mut builder = tar::Builder::new(Vec::new());
+        let mut header = tar::Header::new_gnu();
+        header.set_entry_type(EntryType::Directory);
+        header.set_size(0);
+        header.set_mode(0o755);
+        header.set_cksum();
+        builder
+            .append_data(&mut header, "empty/", io::empty())
+            .unwrap();
+        for (name, data) in files() {
+            let mut header = tar::Header::new_gnu();
+            header.set_size(data.len() as u64);
+            header.set_mode(0o644);
+            header.set_cksum();
+            builder.append_data(&mut header, name, data).unwrap();
+        }
+        builder.into_inner().unwrap()
+    }
+
+    fn tar_gz() -> Vec<u8> {
+        let mut encoder = GzEncoder::new(Vec::new(), flate2::Compression::default());
+        encoder.write_all(&tar()).unwrap();
+        encoder.finish().unwrap()
+    }
+
+    #[test]
+    fn files_inside_archives_match_files_on_disk() {
+        let dir = std::env::temp_dir().join(format!("ssdedupe-archive-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("loose.txt"), SAME).unwrap();
+        fs::write(dir.join("archive.zip"), zip()).unwrap();
+        fs::write(dir.join("archive.tar"), tar()).unwrap();
+        fs::write(dir.join("archive.tar.gz"), tar_gz()).unwrap();
+
+        let options = ScanOptions {
+            archives: true,
+            ..Default::default()
+        };
+        let state = ScanState::new(options, false);
+        let entry = Entry::scan(&dir, &state).unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+        assert!(state.clone_error_log().is_empty());
+        // every archive and the loose file
+        assert_eq!(state.files(), 4);
+
+        let roots = roots([("drive", entry)]);
+        for archive in ["archive.zip", "archive.tar", "archive.tar.gz"] {
+            let archive = roots.get(&Path::new("drive").join(archive)).unwrap();
+            assert_eq!(archive.info().kind, EntryKind::Dir);
+            let file_names = |path: &str| {
+                let dir = archive.get(Path::new(path)).unwrap();
+                dir.children()
+                    .map(|(file_name, _)| file_name.to_string())
+                    .collect::<Vec<_>>()
+            };
+            assert_eq!(file_names(""), ["empty", "inner", "top.txt"]);
+            assert_eq!(file_names("inner"), ["deeper", "same.txt"]);
+            assert_eq!(file_names("empty"), Vec::<String>::new());
+        }
+
+        let loose = roots.get(Path::new("drive/loose.txt")).unwrap().info();
+        let grouped = roots.grouped(&AtomicBool::new(false)).unwrap();
+        let mut same = grouped[&loose]
+            .iter()
+            .map(|&id| roots.path(id))
+            .collect::<Vec<_>>();
+        same.sort();
+        assert_eq!(
+            same,
+            [
+                "drive/archive.tar/inner/same.txt",
+                "drive/archive.tar.gz/inner/same.txt",
+                "drive/archive.zip/inner/same.txt",
+                "drive/loose.txt",
+            ]
+            .map(PathBuf::from)
+        );
+    }
+
+    #[test]
+    fn files_dont_replace_directories_with_the_same_name() {
+        let info = |bytes| file(bytes).info();
+        let mut root = VirtualDir::default();
+        root.insert(Path::new("name/inside"), Some(info(1)));
+        root.insert(Path::new("name"), Some(info(2)));
+        // and the other way around
+        root.insert(Path::new("other"), Some(info(3)));
+        root.insert(Path::new("other/inside"), Some(info(4)));
+        root.insert(Path::new("other"), None);
+
+        let roots = roots([("archive", root.into_entry(DirHashing::Content))]);
+        let get = |path: &str| roots.get(&Path::new("archive").join(path));
+        assert_eq!(get("name").unwrap().info().kind, EntryKind::Dir);
+        assert_eq!(get("name/inside").unwrap().info(), info(1));
+        assert_eq!(get("other").unwrap().info(), info(3));
+        assert!(get("other/inside").is_none());
+    }
+}
@@ -0,0 +1,166 @@
+use std::{
+    collections::HashMap,
+    io,
+    num::NonZeroUsize,
+    path::Path,
+    sync::{Arc, Mutex, Weak},
+    thread::available_parallelism,
+};
+
+use rayon::{ThreadPool, ThreadPoolBuilder};
+
+/// Hands out a separate thread pool for each physical device.
+///
+/// Scans of the same device share its pool, so that they don't thrash the disk by competing for
+/// it, while scans of different devices don't compete for threads.
+#[derive(Default)]
+pub struct Scheduler {
+    devices: Mutex<HashMap<u64, Weak<Device>>>,
+}
+
+impl Scheduler {
+    /// Returns the device that contains `path`.
+    ///
+    /// Devices stay alive for as long as any scan holds on to them.
+    pub fn device(&self, path: &Path) -> io::Result<Arc<Device>> {
+        self.device_by_id(device_id(path)?)
+    }
+
+    fn device_by_id(&self, id: u64) -> io::Result<Arc<Device>> {
+        let mut devices = self.devices.lock().unwrap();
+        devices.retain(|_, device| device.strong_count() > 0);
+        if let Some(device) = devices.get(&id).and_then(Weak::upgrade) {
+            return Ok(device);
+        }
+
+        let device = Arc::new(Device::new(id)?);
+        devices.insert(id, Arc::downgrade(&device));
+        Ok(device)
+    }
+}
+
+pub struct Device {
+    pub pool: ThreadPool,
+    /// Whether this is a spinning disk, which is read sequentially in inode order.
+    ///
+    /// Reading from multiple places at once would only make its head jump back and forth.
+    pub rotational: bool,
+}
+
+impl Device {
+    fn new(id: u64) -> io::Result<Self> {
+        let rotational = is_rotational(id);
+        let num_threads = if rotational {
+            1
+        } else {
+            available_parallelism().map_or(1, NonZeroUsize::get)
+        };
+
+        let pool = ThreadPoolBuilder::new()
+            .num_threads(num_threads)
+            .thread_name(move |index| format!("scan {id:x} #{index}"))
+            .build()
+            .map_err(io::Error::other)?;
+
+        Ok(Self { pool, rotational })
+    }
+}
+
+#[cfg(unix)]
+fn device_id(path: &Path) -> io::Result<u64> {
+    use std::os::unix::fs::MetadataExt;
+
+    Ok(disk_id(path.metadata()?.dev()))
+}
+
+/// Maps the id of a partition to the id of its disk, since partitions of the same disk compete
+/// for it just like directories do.
+#[cfg(target_os = "linux")]
+fn disk_id(id: u64) -> u64 {
+    use std::fs;
+
+    let Some(dev) = sys_block_dir(id) else {
+        return id;
+    };
+    if !dev.join("partition").exists() {
+        return id;
+    }
+
+    // the disk's directory contains those of its partitions
+    fs::read_to_string(dev.join("../dev"))
+        .ok()
+        .and_then(|disk| {
+            let (major, minor) = disk.trim().split_once(':')?;
+            Some(libc::makedev(major.parse().ok()?, minor.parse().ok()?))
+        })
+        .unwrap_or(id)
+}
+
+#[cfg(all(unix, not(target_os = "linux")))]
+fn disk_id(id: u64) -> u64 {
+    id
+}
+
+/// Identifies devices by the prefix of the path (e.g. `C:`), since there is no stable way to get
+/// the volume of a path.
+#[cfg(not(unix))]
+fn device_id(path: &Path) -> io::Result<u64> {
+    use std::hash::{BuildHasher, BuildHasherDefault, DefaultHasher};
+
+    let path = path.canonicalize()?;
+    Ok(BuildHasherDefault::<DefaultHasher>::default().hash_one(path.components().next()))
+}
+
+#[cfg(target_os = "linux")]
+fn is_rotational(id: u64) -> bool {
+    // e.g. network drives or btrfs, which uses virtual device ids
+    sys_block_dir(id)
+        .and_then(|dev| std::fs::read_to_string(dev.join("queue/rotational")).ok())
+        .is_some_and(|rotational| rotational.trim() == "1")
+}
+
+/// Returns the sysfs directory of a block device, if it is one.
+#[cfg(target_os = "linux")]
+fn sys_block_dir(id: u64) -> Option<std::path::PathBuf> {
+    std::fs::canonicalize(format!(
+        "/sys/dev/block/{}:{}",
+        libc::major(id),
+        libc::minor(id)
+    ))
+    .ok()
+}
+
+#[cfg(not(target_os = "linux"))]
+fn is_rotational(_id: u64) -> bool {
+    false
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn scans_of_the_same_device_share_its_pool() {
+        let scheduler = Scheduler::default();
+        let temp_dir = std::env::temp_dir();
+        let device = scheduler.device(&temp_dir).unwrap();
+        assert!(Arc::ptr_eq(&device, &scheduler.device(&temp_dir).unwrap()));
+
+        let first = scheduler.device_by_id(1).unwrap();
+        assert!(Arc::ptr_eq(&first, &scheduler.device_by_id(1).unwrap()));
+        let second = scheduler.device_by_id(2).unwrap();
+        assert!(!Arc::ptr_eq(&first, &second));
+    }
+
+    #[test]
+    fn devices_are_dropped_after_their_last_scan() {
+        let scheduler = Scheduler::default();
+        let first = scheduler.device_by_id(1).unwrap();
+        let weak = Arc::downgrade(&first);
+        drop(first);
+        assert!(weak.upgrade().is_none());
+
+        let _second = scheduler.device_by_id(2).unwrap();
+        assert_eq!(scheduler.devices.lock().unwrap().len(), 1);
+    }
+}
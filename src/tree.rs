@@ -0,0 +1,1341 @@
+use std::{
+    cmp::Reverse,
+    collections::{BTreeMap, HashMap},
+    iter,
+    path::{Path, PathBuf},
+    sync::{
+        Arc,
+        atomic::{self, AtomicBool},
+    },
+};
+
+use compact_str::CompactString;
+use itertools::Itertools;
+use serde::{Deserialize, Deserializer, Serialize, Serializer, de};
+
+use crate::scan::{Dir, DirHashing, Entry, EntryInfo, EntryKind};
+
+/// Marks missing names, parents, children and siblings.
+const NONE: u32 = u32::MAX;
+
+/// A scanned [`Entry`] stored as flat columns, which takes a fraction of the memory.
+///
+/// Entries link to their parent, their first child and their next sibling, where siblings are
+/// sorted by name. Updates append new entries and unlink the ones they replace, which keep taking
+/// up space until they make up half of the tree and it is compacted.
+#[derive(Clone, Debug)]
+pub struct Tree {
+    columns: Columns,
+    dirs: u64,
+    files: u64,
+    /// Entries that were unlinked by updates.
+    garbage: usize,
+    /// The index of every name, which is only kept once the tree is updated.
+    interned: HashMap<CompactString, u32>,
+}
+
+#[derive(Clone, Debug, Default)]
+struct Columns {
+    /// Distinct file names, which entries refer to by index.
+    names: Vec<CompactString>,
+    nodes: Vec<Node>,
+    bytes: Vec<u64>,
+    hashes: Vec<u64>,
+}
+
+#[derive(Clone, Copy, Debug)]
+struct Node {
+    name: u32,
+    parent: u32,
+    first_child: u32,
+    next_sibling: u32,
+    kind: EntryKind,
+    /// See [`Dir::partial`].
+    partial: bool,
+}
+
+/// How a [`Tree`] is stored in catalogs.
+///
+/// Entries are in pre-order, so that every directory is immediately followed by all of its
+/// descendants, and there are neither unlinked entries nor unused names.
+#[derive(Default, Serialize, Deserialize)]
+struct Stored {
+    names: Vec<CompactString>,
+    nodes: Vec<StoredNode>,
+    bytes: Vec<u64>,
+    hashes: Vec<u64>,
+}
+
+#[derive(Clone, Copy, Serialize, Deserialize)]
+struct StoredNode {
+    name: u32,
+    parent: u32,
+    /// The index after the last descendant.
+    end: u32,
+    kind: EntryKind,
+    partial: bool,
+}
+
+/// A reference to an entry of a [`Tree`].
+#[derive(Clone, Copy, Debug)]
+pub struct EntryRef<'a> {
+    columns: &'a Columns,
+    index: usize,
+}
+
+impl Tree {
+    pub fn new(entry: Entry) -> Self {
+        let mut tree = Self::empty();
+        tree.push_entry(NONE, NONE, entry);
+        // only updated trees need to look up names
+        tree.interned = HashMap::new();
+        tree
+    }
+
+    pub fn root(&self) -> EntryRef<'_> {
+        EntryRef {
+            columns: &self.columns,
+            index: 0,
+        }
+    }
+
+    pub fn dirs(&self) -> u64 {
+        self.dirs
+    }
+
+    pub fn files(&self) -> u64 {
+        self.files
+    }
+
+    /// Applies all `updates` one after another, each of which replaces the entry at a relative
+    /// path or removes it if there is no new entry.
+    ///
+    /// Only the updated entries and their ancestors are touched, so this takes time proportional
+    /// to the changes rather than to the tree. Returns `false` if nothing was updated, because the
+    /// parent directories of all paths are not part of the tree.
+    pub fn update(
+        &mut self,
+        updates: impl IntoIterator<Item = (PathBuf, Option<Entry>)>,
+        dir_hashing: DirHashing,
+    ) -> bool {
+        // directories whose info has to be recalculated, along with their depth
+        let mut outdated = HashMap::new();
+        let mut updated = false;
+        for (path, entry) in updates {
+            updated |= self.update_one(&path, entry, &mut outdated);
+        }
+
+        // children have to be recalculated before their parents
+        let outdated = outdated
+            .into_iter()
+            .sorted_unstable_by_key(|&(_, depth)| Reverse(depth));
+        for (index, _) in outdated {
+            let info = self.columns.dir_info(index, dir_hashing);
+            self.columns.bytes[index] = info.bytes;
+            self.columns.hashes[index] = info.hash;
+        }
+
+        if self.garbage > self.columns.nodes.len() / 2 {
+            *self = Self::from_stored(self.to_stored());
+        }
+
+        updated
+    }
+
+    /// Recalculates the hashes of all directories, e.g. to switch to different [`DirHashing`].
+    ///
+    /// Files are not touched, so this doesn't need access to the scanned files.
+    pub fn rehash_dirs(&mut self, dir_hashing: DirHashing) {
+        let dirs = self
+            .columns
+            .pre_order(0)
+            .map(|(index, _)| index)
+            .filter(|&index| self.columns.nodes[index].kind == EntryKind::Dir)
+            .collect_vec();
+        // children come after their parents, so they are done first
+        for index in dirs.into_iter().rev() {
+            self.columns.hashes[index] = self.columns.dir_info(index, dir_hashing).hash;
+        }
+    }
+
+    fn empty() -> Self {
+        Self {
+            columns: Columns::default(),
+            dirs: 0,
+            files: 0,
+            garbage: 0,
+            interned: HashMap::new(),
+        }
+    }
+
+    /// Updates a single entry, adding the directories that contain it to `outdated`.
+    fn update_one(
+        &mut self,
+        path: &Path,
+        entry: Option<Entry>,
+        outdated: &mut HashMap<usize, usize>,
+    ) -> bool {
+        let components = path.iter().collect_vec();
+        let Some((file_name, dirs)) = components.split_last() else {
+            // the root itself can only be replaced, but not removed
+            let Some(entry) = entry else {
+                return false;
+            };
+            *self = Self::new(entry);
+            // the indices of previous updates don't exist anymore
+            outdated.clear();
+            return true;
+        };
+
+        let mut ancestors = vec![0];
+        for dir in dirs {
+            let name = CompactString::from(dir.to_string_lossy());
+            let Some(child) = self
+                .columns
+                .find_child(ancestors[ancestors.len() - 1], &name)
+                .1
+            else {
+                return false;
+            };
+            ancestors.push(child);
+        }
+
+        let parent = ancestors[ancestors.len() - 1];
+        if self.columns.nodes[parent].kind != EntryKind::Dir {
+            return false;
+        }
+
+        let name = CompactString::from(file_name.to_string_lossy());
+        let (previous, existing) = self.columns.find_child(parent, &name);
+        let mut next = match previous {
+            Some(previous) => self.columns.nodes[previous].next_sibling,
+            None => self.columns.nodes[parent].first_child,
+        };
+        if let Some(existing) = existing {
+            next = self.columns.nodes[existing].next_sibling;
+            for (index, _) in self.columns.pre_order(existing) {
+                match self.columns.nodes[index].kind {
+                    EntryKind::Dir => self.dirs -= 1,
+                    EntryKind::File => self.files -= 1,
+                }
+                self.garbage += 1;
+            }
+        }
+
+        let first = match entry {
+            Some(entry) => {
+                let name = self.intern(&name);
+                let index = self.push_entry(name, parent as u32, entry);
+                self.columns.nodes[index as usize].next_sibling = next;
+                index
+            }
+            None => next,
+        };
+        match previous {
+            Some(previous) => self.columns.nodes[previous].next_sibling = first,
+            None => self.columns.nodes[parent].first_child = first,
+        }
+
+        outdated.extend(
+            ancestors
+                .into_iter()
+                .enumerate()
+                .map(|(depth, dir)| (dir, depth)),
+        );
+        true
+    }
+
+    fn intern(&mut self, name: &str) -> u32 {
+        if self.interned.len() != self.columns.names.len() {
+            self.interned = self
+                .columns
+                .names
+                .iter()
+                .enumerate()
+                .map(|(index, name)| (name.clone(), index as u32))
+                .collect();
+        }
+
+        if let Some(&index) = self.interned.get(name) {
+            return index;
+        }
+
+        let index = self.columns.names.len() as u32;
+        self.columns.names.push(name.into());
+        self.interned.insert(name.into(), index);
+        index
+    }
+
+    /// Adds an entry without children and returns its index.
+    fn push_node(&mut self, name: u32, parent: u32, info: EntryInfo, partial: bool) -> u32 {
+        let index = u32::try_from(self.columns.nodes.len())
+            .ok()
+            .filter(|&index| index < NONE - 1)
+            .expect("tree should have fewer than 2^32 - 1 entries");
+        self.columns.nodes.push(Node {
+            name,
+            parent,
+            first_child: NONE,
+            next_sibling: NONE,
+            kind: info.kind,
+            partial,
+        });
+        self.columns.bytes.push(info.bytes);
+        self.columns.hashes.push(info.hash);
+        match info.kind {
+            EntryKind::Dir => self.dirs += 1,
+            EntryKind::File => self.files += 1,
+        }
+        index
+    }
+
+    /// Adds an entry with all of its descendants and returns its index, leaving it to the caller
+    /// to link it to its siblings.
+    fn push_entry(&mut self, name: u32, parent: u32, entry: Entry) -> u32 {
+        match entry {
+            Entry::File(info) => self.push_node(name, parent, info, false),
+            Entry::Dir(Dir {
+                info,
+                entries,
+                partial,
+                ..
+            }) => {
+                let index = self.push_node(name, parent, info, partial);
+                let mut previous = None;
+                for (file_name, entry) in entries {
+                    let name = self.intern(&file_name);
+                    let child = self.push_entry(name, index, entry);
+                    match previous {
+                        Some(previous) => {
+                            self.columns.nodes[previous as usize].next_sibling = child;
+                        }
+                        None => self.columns.nodes[index as usize].first_child = child,
+                    }
+                    previous = Some(child);
+                }
+                index
+            }
+        }
+    }
+
+    /// Copies all entries that are still linked in pre-order, along with the names they use.
+    fn to_stored(&self) -> Stored {
+        let mut stored = Stored::default();
+        let mut names = vec![NONE; self.columns.names.len()];
+        let mut indices = vec![NONE; self.columns.nodes.len()];
+        // the directories that contain the current entry, which are still missing their end
+        let mut open = Vec::<(usize, usize)>::new();
+
+        for (index, depth) in self.columns.pre_order(0) {
+            let new_index = stored.nodes.len();
+            while open
+                .last()
+                .is_some_and(|&(_, open_depth)| open_depth >= depth)
+            {
+                let (dir, _) = open.pop().unwrap();
+                stored.nodes[dir].end = new_index as u32;
+            }
+
+            let node = self.columns.nodes[index];
+            let name = if node.name == NONE {
+                NONE
+            } else {
+                let name = &mut names[node.name as usize];
+                if *name == NONE {
+                    *name = stored.names.len() as u32;
+                    stored
+                        .names
+                        .push(self.columns.names[node.name as usize].clone());
+                }
+                *name
+            };
+
+            indices[index] = new_index as u32;
+            stored.nodes.push(StoredNode {
+                name,
+                parent: if node.parent == NONE {
+                    NONE
+                } else {
+                    indices[node.parent as usize]
+                },
+                end: new_index as u32 + 1,
+                kind: node.kind,
+                partial: node.partial,
+            });
+            stored.bytes.push(self.columns.bytes[index]);
+            stored.hashes.push(self.columns.hashes[index]);
+            if node.kind == EntryKind::Dir {
+                open.push((new_index, depth));
+            }
+        }
+
+        let len = stored.nodes.len() as u32;
+        for (dir, _) in open {
+            stored.nodes[dir].end = len;
+        }
+        stored
+    }
+
+    /// Links the entries of a [`Stored`] tree, which has to be [valid](Stored::validate).
+    fn from_stored(stored: Stored) -> Self {
+        let mut tree = Self::empty();
+        tree.columns.names = stored.names;
+        tree.columns.bytes = stored.bytes;
+        tree.columns.hashes = stored.hashes;
+        tree.columns.nodes = stored
+            .nodes
+            .iter()
+            .map(|node| Node {
+                name: node.name,
+                parent: node.parent,
+                first_child: NONE,
+                next_sibling: NONE,
+                kind: node.kind,
+                partial: node.partial,
+            })
+            .collect();
+
+        // siblings come one after another in pre-order
+        let mut last_children = vec![NONE; stored.nodes.len()];
+        for (index, node) in stored.nodes.iter().enumerate() {
+            match node.kind {
+                EntryKind::Dir => tree.dirs += 1,
+                EntryKind::File => tree.files += 1,
+            }
+
+            if node.parent == NONE {
+                continue;
+            }
+            let parent = node.parent as usize;
+            match last_children[parent] {
+                NONE => tree.columns.nodes[parent].first_child = index as u32,
+                previous => tree.columns.nodes[previous as usize].next_sibling = index as u32,
+            }
+            last_children[parent] = index as u32;
+        }
+
+        tree
+    }
+}
+
+impl Serialize for Tree {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        self.to_stored().serialize(serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for Tree {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let stored = Stored::deserialize(deserializer)?;
+        stored.validate().map_err(de::Error::custom)?;
+        Ok(Tree::from_stored(stored))
+    }
+}
+
+impl Columns {
+    fn info(&self, index: usize) -> EntryInfo {
+        EntryInfo {
+            bytes: self.bytes[index],
+            kind: self.nodes[index].kind,
+            hash: self.hashes[index],
+        }
+    }
+
+    fn name(&self, index: usize) -> &str {
+        self.names[self.nodes[index].name as usize].as_str()
+    }
+
+    fn parent(&self, index: usize) -> Option<usize> {
+        let parent = self.nodes[index].parent;
+        (parent != NONE).then_some(parent as usize)
+    }
+
+    fn children(&self, index: usize) -> impl Iterator<Item = usize> + Clone + '_ {
+        let link = |link: u32| (link != NONE).then_some(link as usize);
+        std::iter::successors(link(self.nodes[index].first_child), move |&child| {
+            link(self.nodes[child].next_sibling)
+        })
+    }
+
+    /// Finds the child of a directory with the given `name` or where it would have to be
+    /// inserted, which is after the returned previous sibling.
+    fn find_child(&self, index: usize, name: &str) -> (Option<usize>, Option<usize>) {
+        let mut previous = None;
+        for child in self.children(index) {
+            match self.name(child).cmp(name) {
+                std::cmp::Ordering::Less => previous = Some(child),
+                std::cmp::Ordering::Equal => return (previous, Some(child)),
+                std::cmp::Ordering::Greater => break,
+            }
+        }
+        (previous, None)
+    }
+
+    /// Returns the entry at `start` and all of its descendants in pre-order, along with their
+    /// depth below `start`.
+    fn pre_order(&self, start: usize) -> impl Iterator<Item = (usize, usize)> + '_ {
+        let mut next = Some((start, 0));
+        std::iter::from_fn(move || {
+            let (index, depth) = next?;
+            next = self.next_in_pre_order(start, index, depth);
+            Some((index, depth))
+        })
+    }
+
+    fn next_in_pre_order(
+        &self,
+        start: usize,
+        mut index: usize,
+        mut depth: usize,
+    ) -> Option<(usize, usize)> {
+        let first_child = self.nodes[index].first_child;
+        if first_child != NONE {
+            return Some((first_child as usize, depth + 1));
+        }
+
+        while index != start {
+            let node = &self.nodes[index];
+            if node.next_sibling != NONE {
+                return Some((node.next_sibling as usize, depth));
+            }
+            index = node.parent as usize;
+            depth -= 1;
+        }
+        None
+    }
+
+    /// Calculates the info of a directory from its children.
+    fn dir_info(&self, index: usize, dir_hashing: DirHashing) -> EntryInfo {
+        let children = self
+            .children(index)
+            .map(|child| (self.name(child), self.info(child)));
+        EntryInfo::dir(children, dir_hashing)
+    }
+}
+
+impl Stored {
+    /// Checks that the columns form a tree, so that indexing them never panics.
+    fn validate(&self) -> Result<(), &'static str> {
+        let len = self.nodes.len();
+        if len == 0 {
+            return Err("tree has no root");
+        }
+        if u32::try_from(len).is_err() || len >= NONE as usize - 1 {
+            return Err("tree has too many entries");
+        }
+        if self.bytes.len() != len || self.hashes.len() != len {
+            return Err("tree columns have different lengths");
+        }
+
+        // the directories that contain the current node
+        let mut open = Vec::new();
+        for (index, node) in self.nodes.iter().enumerate() {
+            while open
+                .last()
+                .is_some_and(|&dir: &usize| self.nodes[dir].end as usize <= index)
+            {
+                open.pop();
+            }
+
+            let parent = open.last().map_or(NONE, |&dir| dir as u32);
+            if node.parent != parent || (index > 0 && open.is_empty()) {
+                return Err("tree entry has the wrong parent");
+            }
+            if (node.name == NONE) != (index == 0)
+                || (node.name != NONE && node.name as usize >= self.names.len())
+            {
+                return Err("tree entry has an invalid name");
+            }
+
+            let end = node.end as usize;
+            let max_end = open.last().map_or(len, |&dir| self.nodes[dir].end as usize);
+            match node.kind {
+                EntryKind::File if end != index + 1 => {
+                    return Err("tree file has descendants");
+                }
+                EntryKind::Dir if end <= index || end > max_end => {
+                    return Err("tree directory has invalid descendants");
+                }
+                EntryKind::Dir => open.push(index),
+                EntryKind::File => {}
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl<'a> EntryRef<'a> {
+    pub fn info(self) -> EntryInfo {
+        self.columns.info(self.index)
+    }
+
+    pub fn is_partial(self) -> bool {
+        self.columns.nodes[self.index].partial
+    }
+
+    /// Returns the children of a directory with their file name, sorted by name.
+    pub fn children(self) -> impl Iterator<Item = (&'a str, Self)> {
+        let columns = self.columns;
+        columns
+            .children(self.index)
+            .map(move |index| (columns.name(index), Self { columns, index }))
+    }
+
+    /// Returns the entry at the given relative `path`.
+    pub fn get(self, path: &Path) -> Option<Self> {
+        path.components().try_fold(self, |entry, component| {
+            let file_name = component.as_os_str().to_str()?;
+            entry
+                .children()
+                .find(|(name, _)| *name == file_name)
+                .map(|(_, child)| child)
+        })
+    }
+
+    /// Returns all files with their path relative to this entry.
+    pub fn file_paths(self) -> impl Iterator<Item = (EntryInfo, PathBuf)> + 'a {
+        self.hashes_with_root(PathBuf::new())
+            .filter(|(info, _)| info.kind == EntryKind::File)
+    }
+
+    /// Returns this entry and all of its descendants with their path, starting with `path`.
+    fn hashes_with_root(
+        self,
+        mut path: PathBuf,
+    ) -> impl Iterator<Item = (EntryInfo, PathBuf)> + 'a {
+        let columns = self.columns;
+        // the number of names that are currently pushed onto `path`
+        let mut pushed = 0;
+        columns
+            .pre_order(self.index)
+            .filter_map(move |(index, depth)| {
+                if depth > 0 {
+                    for _ in depth - 1..pushed {
+                        path.pop();
+                    }
+                    path.push(columns.name(index));
+                    pushed = depth;
+                }
+
+                // partial directories only match by chance
+                (!columns.nodes[index].partial).then(|| (columns.info(index), path.clone()))
+            })
+    }
+
+    /// Copies this entry into a regular [`Entry`], e.g. to resume a scan.
+    pub fn to_entry(self) -> Entry {
+        let info = self.info();
+        match info.kind {
+            EntryKind::File => Entry::File(info),
+            EntryKind::Dir => {
+                let entries = self
+                    .children()
+                    .map(|(name, child)| (CompactString::from(name), child.to_entry()))
+                    .collect::<BTreeMap<_, _>>();
+                Entry::Dir(Dir {
+                    info,
+                    dirs: 1 + entries.values().map(|entry| entry.dirs()).sum::<u64>(),
+                    files: entries.values().map(|entry| entry.files()).sum(),
+                    entries,
+                    partial: self.is_partial(),
+                })
+            }
+        }
+    }
+}
+
+/// Multiple trees that act like the entries of a single directory, e.g. all enabled drives.
+///
+/// Unlike a combined [`Entry::dir`], this neither copies nor rehashes the trees.
+#[derive(Clone, Debug, Default)]
+pub struct Roots(Vec<(CompactString, Arc<Tree>)>);
+
+/// An entry in [`Roots`], which is a lot cheaper to group and walk up than its path.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct NodeId {
+    root: u32,
+    index: u32,
+}
+
+impl NodeId {
+    /// The index of the root that contains the entry, in the order of [`Roots::iter`].
+    pub fn root(self) -> usize {
+        self.root as usize
+    }
+}
+
+/// A set of duplicates that some of the ancestors of its entries may be, while walking down.
+struct Prefix {
+    set: u32,
+    /// How many levels the set is above the entries.
+    levels: usize,
+    /// The ancestors of the entries that were visited so far, which may contain repetitions.
+    ancestors: Vec<NodeId>,
+}
+
+impl Roots {
+    /// Returns the roots by their name, sorted by name.
+    pub fn iter(&self) -> impl Iterator<Item = (&CompactString, EntryRef<'_>)> {
+        self.0.iter().map(|(name, tree)| (name, tree.root()))
+    }
+
+    /// Returns the entry at the given `path`, which starts with the name of its root.
+    pub fn get(&self, path: &Path) -> Option<EntryRef<'_>> {
+        let mut components = path.iter();
+        let name = components.next()?.to_str()?;
+        let root = self
+            .0
+            .binary_search_by(|(root, _)| root.as_str().cmp(name))
+            .ok()?;
+        self.0[root].1.root().get(components.as_path())
+    }
+
+    /// Returns all entries, keyed by their [`EntryInfo`], including unique ones.
+    ///
+    /// Returns `None` as soon as `canceled` is set.
+    pub fn grouped(&self, canceled: &AtomicBool) -> Option<BTreeMap<EntryInfo, Vec<NodeId>>> {
+        let mut grouped = BTreeMap::<_, Vec<_>>::new();
+        for (root, (_, tree)) in self.0.iter().enumerate() {
+            let columns = &tree.columns;
+            for (index, _) in columns.pre_order(0) {
+                if canceled.load(atomic::Ordering::Relaxed) {
+                    return None;
+                }
+
+                // partial directories only match by chance
+                if !columns.nodes[index].partial {
+                    grouped
+                        .entry(columns.info(index))
+                        .or_default()
+                        .push(NodeId {
+                            root: root as u32,
+                            index: index as u32,
+                        });
+                }
+            }
+        }
+        Some(grouped)
+    }
+
+    /// Returns the path of an entry, which starts with the name of its root.
+    pub fn path(&self, id: NodeId) -> PathBuf {
+        let (name, tree) = &self.0[id.root()];
+        let columns = &tree.columns;
+        let mut names = Vec::new();
+        let mut index = id.index as usize;
+        while let Some(parent) = columns.parent(index) {
+            names.push(columns.name(index));
+            index = parent;
+        }
+        iter::once(name.as_str())
+            .chain(names.into_iter().rev())
+            .collect()
+    }
+
+    /// Returns the file name of an entry, which is the name of its root for the root itself.
+    pub fn file_name(&self, id: NodeId) -> &str {
+        let (name, tree) = &self.0[id.root()];
+        let index = id.index as usize;
+        match tree.columns.parent(index) {
+            Some(_) => tree.columns.name(index),
+            None => name.as_str(),
+        }
+    }
+
+    /// Omits sets of duplicates that are already implied by a higher-level set of duplicates.
+    ///
+    /// For example:
+    ///
+    /// - Given the sets `a/x.txt; b/y.txt` and `a; b`, the former is omitted, since `a; b` already
+    ///   implies that all of their contents match.
+    /// - However, `a/x.txt; b/y.txt; z.txt` is **not** omitted, since `z.txt` is not covered by the
+    ///   `a; b` prefix and therefore adds new information.
+    ///
+    /// Roots can be prefixes of their entries, but there is no common prefix of all roots.
+    ///
+    /// Returns `None` as soon as `canceled` is set.
+    pub fn filter_duplicates_by_prefix(
+        &self,
+        mut duplicates: BTreeMap<EntryInfo, Vec<NodeId>>,
+        canceled: &AtomicBool,
+    ) -> Option<BTreeMap<EntryInfo, Vec<NodeId>>> {
+        // every entry has a single info, so it is part of at most one set
+        let mut set_of_node = self
+            .0
+            .iter()
+            .map(|(_, tree)| vec![NONE; tree.columns.nodes.len()])
+            .collect_vec();
+        for (set, ids) in duplicates.values().enumerate() {
+            for id in ids {
+                set_of_node[id.root()][id.index as usize] = set as u32;
+            }
+        }
+
+        // a set is implied by another one if the ancestors of its entries that are the same
+        // number of levels above them are exactly the entries of the other set, so each set
+        // starts with the sets of the ancestors of its first entry, and every further entry
+        // rules out those that it doesn't have an ancestor in at the same level
+        let mut prefixes = duplicates
+            .values()
+            .map(|_| None::<Vec<Prefix>>)
+            .collect_vec();
+        // the ancestors of the current entry with their set, starting at the root
+        let mut stack = Vec::new();
+        for (root, (_, tree)) in self.0.iter().enumerate() {
+            stack.clear();
+            for (index, depth) in tree.columns.pre_order(0) {
+                if canceled.load(atomic::Ordering::Relaxed) {
+                    return None;
+                }
+
+                stack.truncate(depth);
+                let set = set_of_node[root][index];
+                if set != NONE {
+                    match &mut prefixes[set as usize] {
+                        Some(prefixes) => prefixes.retain_mut(|prefix| {
+                            let Some(&(ancestor, set)) =
+                                depth.checked_sub(prefix.levels).map(|depth| &stack[depth])
+                            else {
+                                return false;
+                            };
+                            if prefix.ancestors.last() != Some(&ancestor) {
+                                prefix.ancestors.push(ancestor);
+                            }
+                            set == prefix.set
+                        }),
+                        prefixes @ None => {
+                            *prefixes = Some(
+                                stack
+                                    .iter()
+                                    .rev()
+                                    .enumerate()
+                                    .filter(|(_, (_, set))| *set != NONE)
+                                    .map(|(level, &(ancestor, set))| Prefix {
+                                        set,
+                                        levels: level + 1,
+                                        ancestors: vec![ancestor],
+                                    })
+                                    .collect(),
+                            );
+                        }
+                    }
+                }
+
+                let id = NodeId {
+                    root: root as u32,
+                    index: index as u32,
+                };
+                stack.push((id, set));
+            }
+        }
+
+        let sizes = duplicates.values().map(Vec::len).collect_vec();
+        let mut keep = prefixes.into_iter().map(|prefixes| {
+            !prefixes.into_iter().flatten().any(|mut prefix| {
+                prefix.ancestors.sort_unstable();
+                prefix.ancestors.dedup();
+                prefix.ancestors.len() == sizes[prefix.set as usize]
+            })
+        });
+        duplicates.retain(|_, _| keep.next().unwrap());
+        Some(duplicates)
+    }
+}
+
+impl FromIterator<(CompactString, Arc<Tree>)> for Roots {
+    fn from_iter<T: IntoIterator<Item = (CompactString, Arc<Tree>)>>(iter: T) -> Self {
+        Self(
+            iter.into_iter()
+                .collect::<BTreeMap<_, _>>()
+                .into_iter()
+                .collect(),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        collections::{BTreeSet, HashSet},
+        mem,
+    };
+
+    use super::*;
+    use crate::scan::tests::{dir, file, roots};
+
+    /// How updates used to be applied to a regular [`Entry`], one after another.
+    fn update_entry(
+        entry: &mut Entry,
+        path: &Path,
+        new_entry: Option<Entry>,
+        dir_hashing: DirHashing,
+    ) -> bool {
+        let mut components = path.iter();
+        let Some(file_name) = components.next() else {
+            let Some(new_entry) = new_entry else {
+                return false;
+            };
+            *entry = new_entry;
+            return true;
+        };
+
+        let Entry::Dir(dir) = entry else {
+            return false;
+        };
+
+        let file_name = CompactString::from(file_name.to_string_lossy());
+        let rest = components.as_path();
+        let updated = if rest.as_os_str().is_empty() {
+            match new_entry {
+                Some(new_entry) => {
+                    dir.entries.insert(file_name, new_entry);
+                }
+                None => {
+                    dir.entries.remove(&file_name);
+                }
+            }
+            true
+        } else {
+            dir.entries
+                .get_mut(&file_name)
+                .is_some_and(|child| update_entry(child, rest, new_entry, dir_hashing))
+        };
+
+        if updated {
+            let Entry::Dir(new_dir) = Entry::dir(mem::take(&mut dir.entries), dir_hashing) else {
+                unreachable!();
+            };
+            *dir = Dir {
+                partial: dir.partial,
+                ..new_dir
+            };
+        }
+        updated
+    }
+
+    fn partial(entry: Entry) -> Entry {
+        let Entry::Dir(dir) = entry else {
+            panic!("only directories can be partial");
+        };
+        Entry::Dir(Dir {
+            partial: true,
+            ..dir
+        })
+    }
+
+    fn sample() -> Entry {
+        dir([
+            ("a", dir([("x", file(1)), ("y", file(2))])),
+            ("b", partial(dir([("x", file(1)), ("z", dir::<&str>([]))]))),
+            ("c", file(3)),
+        ])
+    }
+
+    fn assert_same(tree: &Tree, entry: &Entry) {
+        assert_eq!(
+            format!("{:?}", tree.root().to_entry()),
+            format!("{entry:?}")
+        );
+        assert_eq!(tree.dirs(), entry.dirs());
+        assert_eq!(tree.files(), entry.files());
+    }
+
+    #[test]
+    fn roundtrip_keeps_entries() {
+        let entry = sample();
+        let tree = Tree::new(entry.clone());
+        assert_same(&tree, &entry);
+
+        let bytes = postcard::to_allocvec(&tree).unwrap();
+        let decoded = postcard::from_bytes::<Tree>(&bytes).unwrap();
+        assert_same(&decoded, &entry);
+        assert_eq!(postcard::to_allocvec(&decoded).unwrap(), bytes);
+    }
+
+    #[test]
+    fn invalid_trees_are_rejected() {
+        let valid = || Tree::new(sample()).to_stored();
+        assert!(valid().validate().is_ok());
+
+        let mut stored = valid();
+        stored.nodes.clear();
+        assert!(stored.validate().is_err());
+
+        let mut stored = valid();
+        stored.hashes.pop();
+        assert!(stored.validate().is_err());
+
+        let mut stored = valid();
+        stored.nodes[2].parent = 0;
+        assert!(stored.validate().is_err());
+
+        let mut stored = valid();
+        stored.nodes[1].end = stored.nodes.len() as u32 + 1;
+        assert!(stored.validate().is_err());
+
+        let mut stored = valid();
+        stored.nodes[2].end = 4;
+        assert!(stored.validate().is_err());
+
+        let mut stored = valid();
+        stored.nodes[1].name = stored.names.len() as u32;
+        assert!(stored.validate().is_err());
+
+        let mut stored = valid();
+        stored.nodes[1].name = NONE;
+        assert!(stored.validate().is_err());
+    }
+
+    #[test]
+    fn corrupted_bytes_never_panic() {
+        let bytes = postcard::to_allocvec(&Tree::new(sample())).unwrap();
+        for index in 0..bytes.len() {
+            for value in [0, 1, 2, 0x7f, 0xff] {
+                let mut bytes = bytes.clone();
+                bytes[index] = value;
+                if let Ok(tree) = postcard::from_bytes::<Tree>(&bytes) {
+                    tree.root().to_entry();
+                    tree.root().hashes_with_root(PathBuf::new()).for_each(drop);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn updates_match_entry_update() {
+        let dir_hashing = DirHashing::Names;
+        let batches = [
+            vec![("a/x", Some(file(10)))],
+            vec![("a/w", Some(file(11))), ("c", None), ("a/y", None)],
+            vec![("b/z/new", Some(dir([("deep", file(12))])))],
+            vec![("missing/x", Some(file(13))), ("c/x", Some(file(14)))],
+            vec![("a", None), ("a/x", Some(file(15))), ("d", None)],
+            vec![("b/z", Some(file(16))), ("b/0", Some(file(17)))],
+            vec![("", None)],
+            vec![
+                ("e", Some(file(18))),
+                ("", Some(sample())),
+                ("a/v", Some(file(19))),
+            ],
+        ];
+
+        let mut entry = sample();
+        let mut tree = Tree::new(entry.clone());
+        tree.rehash_dirs(dir_hashing);
+        let mut rehashed = tree.root().to_entry();
+        mem::swap(&mut entry, &mut rehashed);
+
+        for batch in batches {
+            let expected = batch.iter().fold(false, |updated, (path, new_entry)| {
+                update_entry(&mut entry, Path::new(path), new_entry.clone(), dir_hashing) | updated
+            });
+            let updated = tree.update(
+                batch
+                    .into_iter()
+                    .map(|(path, new_entry)| (PathBuf::from(path), new_entry)),
+                dir_hashing,
+            );
+            assert_eq!(updated, expected);
+            assert_same(&tree, &entry);
+        }
+    }
+
+    #[test]
+    fn updated_tree_is_stored_like_a_new_one() {
+        let mut entry = sample();
+        let mut tree = Tree::new(entry.clone());
+        let updates = [
+            ("a/x", None),
+            ("a/new", Some(dir([("1", file(20)), ("2", file(21))]))),
+            ("b", Some(file(22))),
+        ];
+        for (path, new_entry) in updates.clone() {
+            update_entry(&mut entry, Path::new(path), new_entry, DirHashing::Content);
+        }
+        tree.update(
+            updates.map(|(path, new_entry)| (PathBuf::from(path), new_entry)),
+            DirHashing::Content,
+        );
+
+        assert_eq!(
+            postcard::to_allocvec(&tree).unwrap(),
+            postcard::to_allocvec(&Tree::new(entry)).unwrap()
+        );
+    }
+
+    #[test]
+    fn replaced_entries_and_names_are_compacted() {
+        let mut tree = Tree::new(sample());
+        for index in 0..1000 {
+            let updates = [
+                (PathBuf::from(format!("a/{index}")), Some(file(index))),
+                (PathBuf::from(format!("a/{}", index.wrapping_sub(1))), None),
+            ];
+            tree.update(updates, DirHashing::Content);
+        }
+
+        assert_eq!(tree.files(), 5);
+        assert!(tree.columns.nodes.len() < 20);
+        assert!(tree.columns.names.len() < 20);
+    }
+
+    /// How duplicates used to be filtered by prefix, using their paths.
+    fn filter_by_path_prefix(
+        mut duplicates: BTreeMap<EntryInfo, BTreeSet<PathBuf>>,
+    ) -> BTreeMap<EntryInfo, BTreeSet<PathBuf>> {
+        let prefixes = duplicates.values().cloned().collect::<HashSet<_>>();
+
+        duplicates.retain(|_, paths| {
+            let mut paths = paths.clone();
+            loop {
+                let Some(new_paths) = paths
+                    .into_iter()
+                    .map(|mut path| (path.pop() && path.file_name().is_some()).then_some(path))
+                    .collect()
+                else {
+                    break true;
+                };
+
+                if prefixes.contains(&new_paths) {
+                    break false;
+                }
+
+                paths = new_paths;
+            }
+        });
+
+        duplicates
+    }
+
+    fn paths(
+        roots: &Roots,
+        duplicates: BTreeMap<EntryInfo, Vec<NodeId>>,
+    ) -> BTreeMap<EntryInfo, BTreeSet<PathBuf>> {
+        duplicates
+            .into_iter()
+            .map(|(info, ids)| (info, ids.into_iter().map(|id| roots.path(id)).collect()))
+            .collect()
+    }
+
+    /// Filters the duplicates of `roots` by prefix and checks that the result is the same as
+    /// with paths.
+    fn filtered(roots: &Roots) -> Vec<Vec<String>> {
+        let canceled = AtomicBool::new(false);
+        let unfiltered = Entry::unfiltered_duplicates(roots.grouped(&canceled).unwrap());
+        let expected = filter_by_path_prefix(paths(roots, unfiltered.clone()));
+        let filtered = roots
+            .filter_duplicates_by_prefix(unfiltered, &canceled)
+            .unwrap();
+        let filtered = paths(roots, filtered);
+        assert_eq!(filtered, expected);
+
+        let mut filtered = filtered
+            .into_values()
+            .map(|paths| {
+                paths
+                    .iter()
+                    .map(|path| path.to_string_lossy().into_owned())
+                    .collect_vec()
+            })
+            .collect_vec();
+        filtered.sort_unstable();
+        filtered
+    }
+
+    #[test]
+    fn grouped_entries_have_their_paths() {
+        let roots = roots([("a", sample()), ("b", sample()), ("c", file(1))]);
+        let expected = roots
+            .iter()
+            .flat_map(|(name, root)| root.hashes_with_root(PathBuf::from(name.as_str())))
+            .into_grouping_map()
+            .collect::<BTreeSet<_>>()
+            .into_iter()
+            .collect::<BTreeMap<_, _>>();
+        let grouped = roots.grouped(&AtomicBool::new(false)).unwrap();
+        for ids in grouped.values() {
+            for &id in ids {
+                let path = roots.path(id);
+                assert_eq!(path.file_name().unwrap(), roots.file_name(id));
+                assert!(roots.get(&path).is_some());
+            }
+        }
+        assert_eq!(paths(&roots, grouped), expected);
+    }
+
+    #[test]
+    fn name_hashing_separates_folders_with_different_names() {
+        let content = |first: &str, second: &str| dir([(first, file(1)), (second, file(2))]);
+        let mut tree = Tree::new(dir([
+            ("a", content("x", "y")),
+            ("b", content("p", "q")),
+            ("c", content("x", "y")),
+        ]));
+        let duplicate_dirs = |tree: &Tree| {
+            let roots = [(CompactString::from("d"), Arc::new(tree.clone()))]
+                .into_iter()
+                .collect::<Roots>();
+            let grouped = roots.grouped(&AtomicBool::new(false)).unwrap();
+            paths(&roots, grouped)
+                .into_iter()
+                .filter(|(info, paths)| info.kind == EntryKind::Dir && paths.len() > 1)
+                .map(|(_, paths)| paths.into_iter().collect::<Vec<_>>())
+                .collect::<Vec<_>>()
+        };
+        let by_content = [vec![
+            PathBuf::from("d/a"),
+            PathBuf::from("d/b"),
+            PathBuf::from("d/c"),
+        ]];
+        assert_eq!(duplicate_dirs(&tree), by_content);
+
+        tree.rehash_dirs(DirHashing::Names);
+        assert_eq!(
+            duplicate_dirs(&tree),
+            [vec![PathBuf::from("d/a"), PathBuf::from("d/c")]]
+        );
+        // the same as scanning with names in the first place
+        let named = |entries: Vec<(&str, Entry)>| {
+            let entries = entries
+                .into_iter()
+                .map(|(file_name, entry)| (file_name.into(), entry))
+                .collect();
+            Entry::dir(entries, DirHashing::Names)
+        };
+        let content = |first, second| named(vec![(first, file(1)), (second, file(2))]);
+        let scanned = named(vec![
+            ("a", content("x", "y")),
+            ("b", content("p", "q")),
+            ("c", content("x", "y")),
+        ]);
+        assert_eq!(tree.root().info(), scanned.info());
+
+        tree.rehash_dirs(DirHashing::Content);
+        assert_eq!(duplicate_dirs(&tree), by_content);
+    }
+
+    #[test]
+    fn nested_duplicates_are_implied_by_their_parents() {
+        let content = || dir([("1", file(1)), ("2", file(2)), ("s", dir([("3", file(3))]))]);
+        let roots = roots([
+            ("a", dir([("x", content()), ("y", content())])),
+            ("b", dir([("z", content()), ("w", file(9))])),
+        ]);
+        assert_eq!(filtered(&roots), [["a/x", "a/y", "b/z"]]);
+    }
+
+    #[test]
+    fn partially_duplicated_dirs_keep_their_shared_files() {
+        let roots = roots([
+            (
+                "a",
+                dir([
+                    ("x", dir([("1", file(1)), ("2", file(2))])),
+                    ("y", dir([("1", file(1)), ("3", file(3))])),
+                ]),
+            ),
+            ("b", dir([("z", dir([("1", file(1)), ("2", file(2))]))])),
+        ]);
+        assert_eq!(
+            filtered(&roots),
+            [vec!["a/x", "b/z"], vec!["a/x/1", "a/y/1", "b/z/1"]]
+        );
+    }
+
+    #[test]
+    fn roots_are_prefixes_but_have_no_common_prefix() {
+        let roots = roots([
+            ("a", dir([("1", file(1)), ("2", file(2))])),
+            ("b", dir([("1", file(1)), ("2", file(2))])),
+            ("c", dir([("1", file(1))])),
+            ("d", file(2)),
+        ]);
+        assert_eq!(
+            filtered(&roots),
+            [
+                vec!["a", "b"],
+                vec!["a/1", "b/1", "c/1"],
+                vec!["a/2", "b/2", "d"]
+            ]
+        );
+    }
+
+    /// A tiny generator, so that the same trees are generated every time.
+    struct Random(u64);
+
+    impl Random {
+        fn below(&mut self, n: usize) -> usize {
+            self.0 = self
+                .0
+                .wrapping_mul(6364136223846793005)
+                .wrapping_add(1442695040888963407);
+            (self.0 >> 33) as usize % n
+        }
+    }
+
+    #[test]
+    fn random_duplicates_are_filtered_like_paths() {
+        for seed in 0..200 {
+            let mut random = Random(seed);
+            let mut entries = Vec::<Entry>::new();
+            for _ in 0..30 {
+                let children = (0..=random.below(3))
+                    .map(|index| {
+                        let child = match random.below(entries.len() + 2) {
+                            0 | 1 => file(random.below(4) as u64),
+                            index => entries[index - 2].clone(),
+                        };
+                        (index.to_string(), child)
+                    })
+                    .collect_vec();
+                let entry = match dir(children) {
+                    entry if entry.files() > 100 => file(random.below(4) as u64),
+                    entry if random.below(8) == 0 => partial(entry),
+                    entry => entry,
+                };
+                entries.push(entry);
+            }
+
+            let mut trees = entries
+                .iter()
+                .rev()
+                .take(3)
+                .map(|entry| Tree::new(entry.clone()))
+                .collect_vec();
+            // updated trees have their entries in a different order
+            trees[0].update(
+                [
+                    (PathBuf::from("0"), None),
+                    (
+                        PathBuf::from("new"),
+                        Some(entries[random.below(30)].clone()),
+                    ),
+                ],
+                DirHashing::Content,
+            );
+
+            let roots = ["a", "b", "c"]
+                .into_iter()
+                .map(CompactString::from)
+                .zip(trees.into_iter().map(Arc::new))
+                .collect::<Roots>();
+            filtered(&roots);
+        }
+    }
+
+    #[test]
+    fn canceled_grouping_and_filtering_stop() {
+        let roots = roots([("a", sample()), ("b", sample())]);
+        let canceled = AtomicBool::new(false);
+        let grouped = roots.grouped(&canceled).unwrap();
+
+        canceled.store(true, atomic::Ordering::Relaxed);
+        assert!(roots.grouped(&canceled).is_none());
+        assert!(
+            roots
+                .filter_duplicates_by_prefix(Entry::unfiltered_duplicates(grouped), &canceled)
+                .is_none()
+        );
+    }
+}
@@ -1,4 +1,7 @@
-use std::thread::{self, JoinHandle};
+use std::{
+    io,
+    thread::{self, JoinHandle},
+};
 
 pub trait TryJoin<T> {
     fn try_join(&mut self) -> Option<thread::Result<T>>;
@@ -10,3 +13,40 @@ impl<T> TryJoin<T> for Option<JoinHandle<T>> {
             .map(JoinHandle::join)
     }
 }
+
+/// Switches the I/O priority of the current thread between idle and the default.
+#[cfg(target_os = "linux")]
+pub fn set_idle_io_priority(idle: bool) -> io::Result<()> {
+    const IOPRIO_WHO_PROCESS: libc::c_int = 1;
+    const IOPRIO_CLASS_SHIFT: libc::c_int = 13;
+    const IOPRIO_CLASS_NONE: libc::c_int = 0;
+    const IOPRIO_CLASS_IDLE: libc::c_int = 3;
+
+    let class = if idle {
+        IOPRIO_CLASS_IDLE
+    } else {
+        IOPRIO_CLASS_NONE
+    };
+
+    // SAFETY: ioprio_set only takes integers; a process id of 0 refers to the calling thread
+    let result = unsafe {
+        libc::syscall(
+            libc::SYS_ioprio_set,
+            IOPRIO_WHO_PROCESS,
+            0,
+            class << IOPRIO_CLASS_SHIFT,
+        )
+    };
+
+    if result == -1 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(())
+    }
+}
+
+/// Switches the I/O priority of the current thread between idle and the default.
+#[cfg(not(target_os = "linux"))]
+pub fn set_idle_io_priority(_idle: bool) -> io::Result<()> {
+    Err(io::ErrorKind::Unsupported.into())
+}
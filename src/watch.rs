@@ -0,0 +1,433 @@
+use std::{
+    collections::BTreeMap,
+    io,
+    path::{Path, PathBuf},
+    sync::mpsc::{self, Receiver, RecvTimeoutError, Sender, TryRecvError},
+    thread,
+    time::{Duration, Instant},
+};
+
+use egui::Context;
+use notify::{
+    Event, EventKind, RecommendedWatcher, RecursiveMode, Watcher,
+    event::{AccessKind, AccessMode, ModifyKind},
+};
+
+use crate::{
+    catalog::Catalog,
+    scan::{Entry, ScanOptions, ScanState},
+};
+
+/// How long to wait for further changes before rescanning.
+///
+/// This way e.g. a file that is written to in lots of small chunks is only rescanned once.
+const DEBOUNCE: Duration = Duration::from_millis(500);
+
+/// Rescan at least this often, even if changes keep coming in.
+const MAX_DEBOUNCE: Duration = Duration::from_secs(5);
+
+/// Save the catalog at most this often, since that rewrites all of it.
+///
+/// Whatever changed since is saved once the watch is stopped.
+const SAVE_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Watches a scanned directory tree for changes, rescans whatever changed and applies and saves
+/// that to a copy of its catalog in the background.
+pub struct Watch {
+    /// Dropping the watcher disconnects the event channel, which makes the watch thread save what
+    /// changed and stop.
+    watcher: Option<RecommendedWatcher>,
+    updates: Receiver<Update>,
+    /// Whether the watch thread stopped, so that no more updates follow.
+    finished: bool,
+}
+
+impl Watch {
+    /// Starts watching the root of `catalog`, whose tree has to be loaded, rescanning changes
+    /// with its options and saving them to `path`.
+    pub fn new(path: PathBuf, catalog: Catalog, ctx: Context) -> notify::Result<Self> {
+        let root = catalog
+            .root
+            .clone()
+            .expect("watched catalog should have a root");
+        let (event_sender, events) = mpsc::channel();
+        let mut watcher = notify::recommended_watcher(event_sender)?;
+        watcher.watch(&root, RecursiveMode::Recursive)?;
+
+        let (update_sender, updates) = mpsc::channel();
+        thread::spawn(move || watch_changes(&path, catalog, &events, update_sender, &ctx));
+
+        Ok(Self {
+            watcher: Some(watcher),
+            updates,
+            finished: false,
+        })
+    }
+
+    /// Stops watching, after changes that were already noticed are rescanned and saved.
+    ///
+    /// The last [update](Watch::updates) has the resulting catalog.
+    pub fn stop(&mut self) {
+        self.watcher = None;
+    }
+
+    pub fn is_stopping(&self) -> bool {
+        self.watcher.is_none()
+    }
+
+    /// Whether the watch stopped and all of its updates were returned.
+    pub fn is_finished(&self) -> bool {
+        self.finished
+    }
+
+    /// Returns all updates since the last call.
+    pub fn updates(&mut self) -> Vec<Update> {
+        let mut updates = Vec::new();
+        loop {
+            match self.updates.try_recv() {
+                Ok(update) => updates.push(update),
+                Err(TryRecvError::Empty) => break,
+                Err(TryRecvError::Disconnected) => {
+                    self.finished = true;
+                    break;
+                }
+            }
+        }
+        updates
+    }
+}
+
+/// What the watch thread did since its last update.
+pub struct Update {
+    /// The catalog with all changes so far, if it was saved.
+    pub catalog: Option<Catalog>,
+    pub error_log: Vec<String>,
+}
+
+/// Rescans changes in batches and applies them to `catalog`, which is saved to `path` at most
+/// every [`SAVE_INTERVAL`] and once `events` disconnects.
+fn watch_changes(
+    path: &Path,
+    mut catalog: Catalog,
+    events: &Receiver<notify::Result<Event>>,
+    updates: Sender<Update>,
+    ctx: &Context,
+) {
+    let root = catalog.root.clone().unwrap();
+    let mut saved = None::<Instant>;
+    // whether the catalog changed since it was saved
+    let mut changed = false;
+    let mut stopped = false;
+    while !stopped {
+        let event = if changed {
+            let until_save = saved.map_or(Duration::ZERO, |saved| {
+                SAVE_INTERVAL.saturating_sub(saved.elapsed())
+            });
+            match events.recv_timeout(until_save) {
+                Ok(event) => Some(event),
+                Err(RecvTimeoutError::Timeout) => None,
+                Err(RecvTimeoutError::Disconnected) => break,
+            }
+        } else {
+            match events.recv() {
+                Ok(event) => Some(event),
+                Err(_) => break,
+            }
+        };
+
+        let mut error_log = Vec::new();
+        if let Some(event) = event {
+            let start = Instant::now();
+            let mut changes = Changes::default();
+            changes.add(&root, event);
+
+            while start.elapsed() < MAX_DEBOUNCE {
+                match events.recv_timeout(DEBOUNCE) {
+                    Ok(event) => changes.add(&root, event),
+                    Err(RecvTimeoutError::Timeout) => break,
+                    Err(RecvTimeoutError::Disconnected) => {
+                        stopped = true;
+                        break;
+                    }
+                }
+            }
+
+            let (entries, rescan_errors) = changes.rescan(&root, catalog.options);
+            error_log = rescan_errors;
+            // the catalog that was saved last is still shared with the UI, so the tree is copied
+            // here rather than on the UI thread
+            changed |= catalog.update_tree(entries);
+        }
+
+        let save =
+            changed && (stopped || saved.is_none_or(|saved| saved.elapsed() >= SAVE_INTERVAL));
+        if !save && error_log.is_empty() {
+            continue;
+        }
+
+        let update = Update {
+            catalog: save.then(|| save_catalog(path, &catalog, &mut error_log)),
+            error_log,
+        };
+        if save {
+            saved = Some(Instant::now());
+            changed = false;
+        }
+        if updates.send(update).is_err() {
+            return;
+        }
+        ctx.request_repaint();
+    }
+
+    if changed {
+        let mut error_log = Vec::new();
+        let catalog = Some(save_catalog(path, &catalog, &mut error_log));
+        let _ = updates.send(Update { catalog, error_log });
+    }
+    // disconnecting first lets the UI notice that the watch stopped
+    drop(updates);
+    ctx.request_repaint();
+}
+
+/// Saves `catalog` to `path`, logging why that failed otherwise, and returns a copy of it that
+/// shares its tree.
+fn save_catalog(path: &Path, catalog: &Catalog, error_log: &mut Vec<String>) -> Catalog {
+    if let Err(error) = catalog.save(path) {
+        error_log.push(error.to_string());
+    }
+    catalog.clone()
+}
+
+/// Changed paths and whether they changed structurally.
+///
+/// Directories are only rescanned on structural changes (creation, removal and renaming), since
+/// changes to their contents show up as separate events.
+#[derive(Default)]
+struct Changes {
+    paths: BTreeMap<PathBuf, bool>,
+    error_log: Vec<String>,
+}
+
+impl Changes {
+    fn add(&mut self, root: &Path, event: notify::Result<Event>) {
+        let event = match event {
+            Ok(event) => event,
+            Err(error) => {
+                self.error_log
+                    .push(format!("failed to watch for changes: {error}"));
+                return;
+            }
+        };
+
+        if event.need_rescan() {
+            // events were lost; only a full rescan can catch up
+            self.paths.insert(root.to_path_buf(), true);
+            return;
+        }
+
+        let structural = match event.kind {
+            EventKind::Access(AccessKind::Close(AccessMode::Write)) => false,
+            EventKind::Access(_) | EventKind::Modify(ModifyKind::Metadata(_)) => return,
+            EventKind::Modify(ModifyKind::Name(_)) => true,
+            EventKind::Modify(_) => false,
+            _ => true,
+        };
+
+        for path in event.paths {
+            *self.paths.entry(path).or_default() |= structural;
+        }
+    }
+
+    /// Returns paths relative to `root` and their rescanned entry or `None` if they no longer
+    /// exist, ready to be passed to [`Catalog::update_tree`], along with what went wrong.
+    fn rescan(
+        self,
+        root: &Path,
+        options: ScanOptions,
+    ) -> (Vec<(PathBuf, Option<Entry>)>, Vec<String>) {
+        let mut error_log = self.error_log;
+        let mut entries = Vec::new();
+        let mut rescanned: Option<PathBuf> = None;
+
+        // paths are sorted, so all descendants of a path immediately follow it
+        for (path, structural) in self.paths {
+            if rescanned
+                .as_ref()
+                .is_some_and(|rescanned| path.starts_with(rescanned))
+            {
+                continue;
+            }
+
+            let Ok(relative_path) = path.strip_prefix(root) else {
+                continue;
+            };
+
+            let entry = match path.symlink_metadata() {
+                Ok(metadata) if metadata.is_dir() && !structural => continue,
+                Ok(_) => {
+                    let state = ScanState::new(options, false);
+                    let entry = Entry::scan(&path, &state);
+                    error_log.extend(state.clone_error_log());
+                    let Some(entry) = entry else {
+                        continue;
+                    };
+                    Some(entry)
+                }
+                Err(error) if error.kind() == io::ErrorKind::NotFound => None,
+                Err(error) => {
+                    error_log.push(format!(
+                        "failed to read metadata of {}: {error}",
+                        path.display()
+                    ));
+                    continue;
+                }
+            };
+
+            entries.push((relative_path.to_path_buf(), entry));
+            rescanned = Some(path);
+        }
+
+        (entries, error_log)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use notify::event::{CreateKind, DataChange, Flag, RemoveKind};
+
+    use super::*;
+    use crate::{catalog::Compression, tree::Tree};
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir =
+            std::env::temp_dir().join(format!("ssdedupe-watch-{name}-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(dir.join("root/sub")).unwrap();
+        dir
+    }
+
+    fn event(kind: EventKind, path: PathBuf) -> notify::Result<Event> {
+        Ok(Event::new(kind).add_path(path))
+    }
+
+    #[test]
+    fn changes_are_rescanned_and_saved_once_stopped() {
+        let dir = temp_dir("save");
+        let root = dir.join("root");
+        fs::write(root.join("a.txt"), "a").unwrap();
+        fs::write(root.join("b.txt"), "b").unwrap();
+        fs::write(root.join("sub/c.txt"), "c").unwrap();
+        let options = ScanOptions::default();
+        let entry = Entry::scan(&root, &ScanState::new(options, false)).unwrap();
+        let catalog = Catalog::new(
+            Some(root.clone()),
+            options,
+            Compression::None,
+            Tree::new(entry),
+        );
+
+        fs::write(root.join("a.txt"), "changed").unwrap();
+        fs::write(root.join("sub/d.txt"), "d").unwrap();
+        fs::remove_file(root.join("b.txt")).unwrap();
+        let (event_sender, events) = mpsc::channel();
+        for (kind, path) in [
+            (
+                EventKind::Modify(ModifyKind::Data(DataChange::Content)),
+                "a.txt",
+            ),
+            (EventKind::Create(CreateKind::File), "sub/d.txt"),
+            (EventKind::Remove(RemoveKind::File), "b.txt"),
+            // the contents of folders change along with their files
+            (EventKind::Modify(ModifyKind::Data(DataChange::Any)), "sub"),
+        ] {
+            event_sender.send(event(kind, root.join(path))).unwrap();
+        }
+        // like dropping the watcher
+        drop(event_sender);
+
+        let path = dir.join("drive.fsinfo");
+        let (update_sender, updates) = mpsc::channel();
+        watch_changes(&path, catalog, &events, update_sender, &Context::default());
+        let mut updates = updates.try_iter().collect::<Vec<_>>();
+        assert_eq!(updates.len(), 1);
+        let update = updates.pop().unwrap();
+        assert!(update.error_log.is_empty(), "{:?}", update.error_log);
+
+        let mut saved = Catalog::open(&path).unwrap();
+        saved.load_tree(&path).unwrap();
+        for catalog in [update.catalog.unwrap(), saved] {
+            let tree = catalog.tree().unwrap();
+            let get = |path: &str| tree.root().get(Path::new(path));
+            assert_eq!(get("a.txt").unwrap().info().bytes, 7);
+            assert!(get("b.txt").is_none());
+            assert!(get("sub/c.txt").is_some());
+            assert!(get("sub/d.txt").is_some());
+            assert_eq!(catalog.summary().files, 3);
+        }
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn nothing_is_saved_without_changes() {
+        let dir = temp_dir("unchanged");
+        let root = dir.join("root");
+        let options = ScanOptions::default();
+        let entry = Entry::scan(&root, &ScanState::new(options, false)).unwrap();
+        let catalog = Catalog::new(Some(root), options, Compression::None, Tree::new(entry));
+
+        let (event_sender, events) = mpsc::channel();
+        drop(event_sender);
+        let path = dir.join("drive.fsinfo");
+        let (update_sender, updates) = mpsc::channel();
+        watch_changes(&path, catalog, &events, update_sender, &Context::default());
+        assert_eq!(updates.try_iter().count(), 0);
+        assert!(!path.exists());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn rescanned_folders_include_changes_below_them() {
+        let dir = temp_dir("folders");
+        let root = dir.join("root");
+        fs::write(root.join("sub/x.txt"), "x").unwrap();
+        fs::write(root.join("sub/y.txt"), "y").unwrap();
+
+        let mut changes = Changes::default();
+        changes.add(
+            &root,
+            event(EventKind::Create(CreateKind::Folder), root.join("sub")),
+        );
+        changes.add(
+            &root,
+            event(EventKind::Create(CreateKind::File), root.join("sub/x.txt")),
+        );
+        // reading doesn't change anything
+        changes.add(
+            &root,
+            event(EventKind::Access(AccessKind::Any), root.join("other.txt")),
+        );
+        changes.add(&root, Err(notify::Error::generic("lost")));
+        let (entries, error_log) = changes.rescan(&root, ScanOptions::default());
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].0, Path::new("sub"));
+        assert_eq!(entries[0].1.as_ref().unwrap().files(), 2);
+        assert_eq!(error_log.len(), 1);
+
+        // lost events can only be caught up on by rescanning everything
+        let mut changes = Changes::default();
+        changes.add(
+            &root,
+            Ok(Event::new(EventKind::Other).set_flag(Flag::Rescan)),
+        );
+        changes.add(
+            &root,
+            event(EventKind::Create(CreateKind::File), root.join("sub/x.txt")),
+        );
+        let (entries, _) = changes.rescan(&root, ScanOptions::default());
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].0, Path::new(""));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}